@@ -0,0 +1,30 @@
+// matching speed for states with few transitions, kept in a sorted Vec,
+// against states with many, kept in a HashMap
+#[macro_use]
+extern crate criterion;
+extern crate regexpr;
+
+use criterion::Criterion;
+use regexpr::automaton::NodeAllocator;
+use regexpr::automaton::build_dfa;
+use regexpr::automaton::build_nfa;
+use regexpr::engine::Engine;
+use regexpr::parser::parse;
+
+fn engine(pattern: &str) -> Engine {
+    let expr = parse(&mut pattern.chars()).unwrap();
+    Engine::new(build_dfa(&build_nfa(&expr, &mut NodeAllocator::new())))
+}
+
+fn transitions(c: &mut Criterion) {
+    let input = "ab".repeat(10000);
+    // two transitions per state
+    let sparse = engine("(a|b)*");
+    c.bench_function("sparse", |b| b.iter(|| sparse.match_string(&input)));
+    // 26 transitions per state
+    let dense = engine("[a-z]*");
+    c.bench_function("dense", |b| b.iter(|| dense.match_string(&input)));
+}
+
+criterion_group!(benches, transitions);
+criterion_main!(benches);
@@ -1,9 +1,16 @@
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::collections::BTreeSet;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::cmp;
+use std::fmt;
 use std::io::Write;
 
+use json;
 use parser::RegExpr;
+use positions::PositionMap;
+use ranges;
 
 pub struct NodeAllocator {
     nodes: Vec<Node>,
@@ -28,18 +35,126 @@ impl Node {
     }
 }
 
+// what an NFA edge consumes
+#[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Debug,Hash)]
+pub enum Condition {
+    Epsilon,
+    Char(char),
+    // any single character
+    Any,
+    // any single character not in the set
+    Except(BTreeSet<char>),
+    // any single character in one of the sorted, disjoint inclusive ranges
+    Ranges(Vec<(char, char)>),
+    // consume nothing, but can only be crossed at the start or at the end of the input
+    StartAnchor,
+    EndAnchor,
+}
+
+impl Condition {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            Condition::Epsilon => false,
+            Condition::Char(d) => c == d,
+            Condition::Any => true,
+            Condition::Except(ref set) => !set.contains(&c),
+            Condition::Ranges(ref ranges) => ranges::contains(ranges, c),
+            Condition::StartAnchor | Condition::EndAnchor => false,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Condition::Epsilon => write!(f, "ε"),
+            Condition::Char(c) => write!(f, "{}", c),
+            Condition::Any => write!(f, "."),
+            Condition::Except(ref set) => {
+                write!(f, "[^{}]", set.iter().cloned().collect::<String>())
+            }
+            Condition::Ranges(ref ranges) => {
+                write!(f, "[")?;
+                for &(first, last) in ranges {
+                    write!(f, "{}", DFACondition::Range(first, last))?;
+                }
+                write!(f, "]")
+            }
+            Condition::StartAnchor => write!(f, "^"),
+            Condition::EndAnchor => write!(f, "$"),
+        }
+    }
+}
+
 #[derive(PartialEq,Eq,PartialOrd,Ord,Clone,Debug)]
 pub struct Edge {
-    condition: Option<char>,
+    condition: Condition,
     from: Node,
     to: Node,
 }
 
-#[derive(Debug)]
+// where a capture group is entered or left
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum GroupBoundary {
+    Open(usize),
+    Close(usize),
+}
+
+// what entering a node does to the counter of a counted repetition, see
+// build_nfa_with_counters. a counter is named by the repetition's hub node and
+// is 0, so left out of Counts, whenever a match is outside the repetition.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+enum CounterOp {
+    // only enterable while the count is below the bound, to start an iteration
+    Below(Node, usize),
+    // an iteration is done. the count stops growing at the bound, past which
+    // only whether the minimum was reached matters
+    Increment(Node, usize),
+    // only enterable once the count reached the bound, and resets it
+    Exit(Node, usize),
+}
+
+// the values of the counters that are not 0, by their hub node
+pub type Counts = BTreeMap<Node, usize>;
+
+// the byte spans of the capture groups of a match by group number, see
+// Graph::captures
+pub type GroupSpans = Vec<Option<(usize, usize)>>;
+
+#[derive(Debug,PartialEq,Eq,Clone)]
 pub struct Graph {
     start: Node,
     edges: BTreeSet<Edge>,
     acceptors: BTreeSet<Node>,
+    // nodes that mark a capture group boundary when a match passes them
+    groups: BTreeMap<Node, GroupBoundary>,
+}
+
+// a Graph from build_nfa_with_counters, with the nodes that count the
+// iterations of its counted repetitions. its language depends on the counters,
+// so it is a type of its own: only the simulation here and CountedNfaEngine
+// follow them, and it cannot be determinized or asked for captures.
+#[derive(Debug,PartialEq,Eq)]
+pub struct CountedGraph {
+    graph: Graph,
+    counters: BTreeMap<Node, CounterOp>,
+}
+
+// what build adds counters for, and the counters added so far
+struct Counting {
+    threshold: usize,
+    counters: BTreeMap<Node, CounterOp>,
+}
+
+// adjacency-list form of a Graph with nodes renumbered from 0.
+// edges[i] lists the (char, target) pairs leaving node i, with None for an
+// epsilon edge, and groups the nodes that mark capture group boundaries.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct NFATable {
+    pub start: usize,
+    pub edges: Vec<Vec<(Option<char>, usize)>>,
+    pub acceptors: BTreeSet<usize>,
+    pub groups: BTreeMap<usize, GroupBoundary>,
 }
 
 #[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Clone)]
@@ -56,20 +171,151 @@ impl DFANode {
         }
     }
 
-    fn pretty_name(&self) -> String {
+    // quoted label listing the NFA nodes this state stands for, e.g. "{ 0,1,3 }"
+    pub fn pretty_name(&self) -> String {
         format!("\"{{ {} }}\"",
                 self.nodes.iter().map(|node| format!("{}", node.id)).collect::<Vec<_>>().join(","))
     }
 }
 
-#[derive(Debug,PartialEq,Eq,PartialOrd,Ord)]
+// how exporters name DFA states
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum LabelFormat {
+    // the set of NFA nodes, see DFANode::pretty_name
+    Set,
+    // the breadth-first id from DFA::state_ids
+    Id,
+}
+
+// what a DFA transition consumes. Range stands for the chars from the first
+// to the last, so large classes stay a single edge, and Other for every char
+// without a Char or Range transition out of the same state, so wildcards do.
+// the anchors consume nothing: StartAnchor leaves the start state before any
+// input is read, EndAnchor is taken once the input is exhausted. when the
+// input is empty the EndAnchor past a StartAnchor also crosses any `^` after `$`.
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Clone,Copy,Hash)]
+pub enum DFACondition {
+    Char(char),
+    Range(char, char),
+    Other,
+    StartAnchor,
+    EndAnchor,
+}
+
+impl fmt::Display for DFACondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DFACondition::Char(c) => write!(f, "{}", c),
+            DFACondition::Range(first, last) if first == last => write!(f, "{}", first),
+            DFACondition::Range(first, last) => write!(f, "{}-{}", first, last),
+            DFACondition::Other => write!(f, "other"),
+            DFACondition::StartAnchor => write!(f, "^"),
+            DFACondition::EndAnchor => write!(f, "$"),
+        }
+    }
+}
+
+// the target of the transition on c among the transitions out of one state
+fn step<'a>(transitions: &BTreeMap<DFACondition, &'a DFANode>, c: char) -> Option<&'a DFANode> {
+    // the ranges sort after the chars and by their first char, so the last
+    // condition up to a range starting at c is the only range that can hold it
+    let range = || match transitions.range(..=DFACondition::Range(c, char::MAX)).next_back() {
+        Some((&DFACondition::Range(_, last), to)) if c <= last => Some(to),
+        _ => None,
+    };
+    transitions.get(&DFACondition::Char(c))
+               .or_else(range)
+               .or_else(|| transitions.get(&DFACondition::Other))
+               .cloned()
+}
+
+// a char of every class of chars the transitions of dfas tell apart: those of
+// Char transitions, and the first other char of every stretch between the ends
+// of Range transitions, including the one before the first of them
+fn representatives(dfas: &[&DFA]) -> BTreeSet<char> {
+    let mut chars = BTreeSet::new();
+    let mut bounds = BTreeSet::new();
+    for edge in dfas.iter().flat_map(|dfa| &dfa.edges) {
+        match edge.condition {
+            DFACondition::Char(c) => {
+                chars.insert(c);
+            }
+            DFACondition::Range(first, last) => {
+                bounds.insert('\0');
+                bounds.insert(first);
+                bounds.extend(ranges::next(last));
+            }
+            _ => {}
+        }
+    }
+    let mut ret = chars.clone();
+    let mut bounds = bounds.into_iter().peekable();
+    while let Some(bound) = bounds.next() {
+        let mut c = Some(bound);
+        while let Some(mentioned) = c.filter(|c| chars.contains(c)) {
+            c = ranges::next(mentioned);
+        }
+        ret.extend(c.filter(|&c| bounds.peek().is_none_or(|&end| c < end)));
+    }
+    ret
+}
+
+// alphabet with the first char it does not contain, preferring letters and digits
+fn with_unmentioned(mut alphabet: BTreeSet<char>) -> BTreeSet<char> {
+    let unmentioned = ('a'..='z').chain('A'..='Z').chain('0'..='9')
+        .chain((0..=0x10ffff).filter_map(char::from_u32))
+        .find(|c| !alphabet.contains(c));
+    alphabet.extend(unmentioned);
+    alphabet
+}
+
+// number of chars, i.e. of unicode scalar values
+const CHAR_COUNT: u64 = 0x110000 - 0x800;
+
+// walks a DFA char by char from the start of the input, taking the
+// anchor transitions where they apply
+struct Walker<'a> {
+    start: &'a DFANode,
+    transitions: BTreeMap<&'a DFANode, BTreeMap<DFACondition, &'a DFANode>>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(dfa: &'a DFA) -> Walker<'a> {
+        Walker {
+            start: &dfa.start,
+            transitions: dfa.transitions(),
+        }
+    }
+
+    fn anchor(&self, state: &'a DFANode, anchor: DFACondition) -> Option<&'a DFANode> {
+        self.transitions.get(state).and_then(|next| next.get(&anchor).cloned())
+    }
+
+    // where matching starts, past any `^`
+    fn start(&self) -> &'a DFANode {
+        self.anchor(self.start, DFACondition::StartAnchor).unwrap_or(self.start)
+    }
+
+    fn step(&self, state: &'a DFANode, c: char) -> Option<&'a DFANode> {
+        self.transitions.get(state).and_then(|next| step(next, c))
+    }
+
+    // whether the input may end in state, possibly through `$`
+    fn accepts(&self, state: &'a DFANode) -> bool {
+        state.is_acceptor || self.anchor(state, DFACondition::EndAnchor).is_some_and(|end| end.is_acceptor)
+    }
+}
+
+#[derive(Debug,PartialEq,Eq,PartialOrd,Ord,Clone)]
 pub struct DFAEdge {
-    pub condition: char,
+    pub condition: DFACondition,
     pub from: DFANode,
     pub to: DFANode,
 }
 
-#[derive(Debug)]
+// DFAs compare equal when they have the same shape, whatever NFA nodes their
+// states came from, see DFA::state_ids
+#[derive(Debug,Clone)]
 pub struct DFA {
     pub start: DFANode,
     pub edges: BTreeSet<DFAEdge>,
@@ -83,41 +329,636 @@ impl DFA {
         }
     }
 
+    // every state reachable from start, including start itself
+    pub fn states(&self) -> BTreeSet<&DFANode> {
+        let mut states: BTreeSet<&DFANode> = BTreeSet::new();
+        states.insert(&self.start);
+        for edge in &self.edges {
+            states.insert(&edge.from);
+            states.insert(&edge.to);
+        }
+        states
+    }
+
+    // transitions out of every state that has any
+    pub fn transitions(&self) -> BTreeMap<&DFANode, BTreeMap<DFACondition, &DFANode>> {
+        let mut transitions: BTreeMap<&DFANode, BTreeMap<DFACondition, &DFANode>> = BTreeMap::new();
+        for edge in &self.edges {
+            transitions.entry(&edge.from).or_default().insert(edge.condition, &edge.to);
+        }
+        transitions
+    }
+
+    // the alphabet and one char it does not mention, which stands in for all
+    // of those since they behave the same in every state
+    pub fn full_alphabet(&self) -> BTreeSet<char> {
+        with_unmentioned(self.alphabet())
+    }
+
+    // checks that no state has two transitions on the same condition,
+    // which Engine::new would otherwise panic on
+    pub fn validate_deterministic(&self) -> Result<(), String> {
+        let mut seen: BTreeMap<(&DFANode, DFACondition), &DFANode> = BTreeMap::new();
+        for edge in &self.edges {
+            if let Some(to) = seen.insert((&edge.from, edge.condition), &edge.to) {
+                return Err(format!("state {} goes to both {} and {} on {}",
+                                   edge.from.pretty_name(),
+                                   to.pretty_name(),
+                                   edge.to.pretty_name(),
+                                   edge.condition));
+            }
+        }
+        for (state, next) in self.transitions() {
+            let mut intervals = next.keys().filter_map(|condition| match *condition {
+                DFACondition::Range(first, last) => Some((first, last)),
+                _ => None,
+            });
+            let mut previous = intervals.next();
+            for (first, last) in intervals {
+                if let Some((before, end)) = previous.filter(|&(_, end)| first <= end) {
+                    return Err(format!("state {} has overlapping transitions on {} and {}",
+                                       state.pretty_name(),
+                                       DFACondition::Range(before, end),
+                                       DFACondition::Range(first, last)));
+                }
+                previous = Some((first, last));
+            }
+        }
+        Ok(())
+    }
+
+    // the chars appearing on any Char transition, and with Range transitions
+    // one char of each stretch they start or end, see representatives
+    pub fn alphabet(&self) -> BTreeSet<char> {
+        representatives(&[self])
+    }
+
+    // states from which every string over the alphabet is accepted, like the
+    // ones reached after the `a` of `a(a|b)*`. in a minimal DFA these are
+    // accepting states looping to themselves on every char; since build_dfa does
+    // not minimize, this keeps the accepting states with a transition on every
+    // char whose successors all stay within the kept set.
+    // when wildcards are involved only an Other transition covers every char.
+    pub fn universal_states(&self) -> Vec<&DFANode> {
+        let alphabet = self.alphabet();
+        let has_other = self.edges.iter().any(|edge| edge.condition == DFACondition::Other);
+        let transitions = self.transitions();
+
+        let mut universal: BTreeSet<&DFANode> = transitions.iter()
+            .filter(|&(state, next)| {
+                state.is_acceptor &&
+                (next.contains_key(&DFACondition::Other) ||
+                 !has_other && alphabet.iter().all(|&c| step(next, c).is_some()))
+            })
+            .map(|(state, _)| *state)
+            .collect();
+        loop {
+            let rejecting: Vec<&DFANode> = universal.iter()
+                .filter(|state| transitions[*state].values().any(|to| !universal.contains(to)))
+                .cloned()
+                .collect();
+            if rejecting.is_empty() {
+                break;
+            }
+            for state in rejecting {
+                universal.remove(state);
+            }
+        }
+        universal.into_iter().collect()
+    }
+
+    // states from which no accepting state can be reached any more, like the
+    // empty state build_dfa adds for the chars a wildcard leaves out
+    pub fn dead_states(&self) -> Vec<&DFANode> {
+        let states = self.states();
+        let mut live: BTreeSet<&DFANode> = states.iter().filter(|state| state.is_acceptor).cloned().collect();
+        loop {
+            let reaching: Vec<&DFANode> = self.edges
+                .iter()
+                .filter(|edge| live.contains(&edge.to) && !live.contains(&edge.from))
+                .map(|edge| &edge.from)
+                .collect();
+            if reaching.is_empty() {
+                break;
+            }
+            live.extend(reaching);
+        }
+        states.into_iter().filter(|state| !live.contains(state)).collect()
+    }
+
+    // whether infinitely many strings are accepted, that is whether some loop
+    // can be entered from start and left towards an accepting state.
+    // anchors are zero-width, so loops through them do not count.
+    pub fn is_infinite(&self) -> bool {
+        let dead: BTreeSet<&DFANode> = self.dead_states().into_iter().collect();
+        let reachable = self.state_ids();
+        let mut successors: BTreeMap<&DFANode, BTreeSet<&DFANode>> = BTreeMap::new();
+        for edge in &self.edges {
+            let consuming = match edge.condition {
+                DFACondition::Char(_) | DFACondition::Range(..) | DFACondition::Other => true,
+                DFACondition::StartAnchor | DFACondition::EndAnchor => false,
+            };
+            if consuming && reachable.contains_key(&edge.from) && !dead.contains(&edge.to) {
+                successors.entry(&edge.from).or_default().insert(&edge.to);
+            }
+        }
+        // peel off states without successors until only loops remain
+        loop {
+            let sinks: Vec<&DFANode> = reachable.keys()
+                .filter(|state| successors.contains_key(*state))
+                .filter(|state| successors[*state].iter().all(|to| !successors.contains_key(to)))
+                .cloned()
+                .collect();
+            if sinks.is_empty() {
+                return !successors.is_empty();
+            }
+            for state in sinks {
+                successors.remove(state);
+            }
+        }
+    }
+
+    // numbers the states in breadth-first order from start, following
+    // transitions in char order, so the ids only depend on the automaton's shape
+    pub fn state_ids(&self) -> BTreeMap<&DFANode, usize> {
+        let transitions = self.transitions();
+
+        let mut ids = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        ids.insert(&self.start, 0);
+        queue.push_back(&self.start);
+        while let Some(state) = queue.pop_front() {
+            if let Some(next) = transitions.get(state) {
+                for &to in next.values() {
+                    if !ids.contains_key(to) {
+                        let id = ids.len();
+                        ids.insert(to, id);
+                        queue.push_back(to);
+                    }
+                }
+            }
+        }
+        ids
+    }
+
+    // the transitions and the accepting states by state_ids
+    fn canonical_form(&self) -> (BTreeSet<(usize, DFACondition, usize)>, BTreeSet<usize>) {
+        let ids = self.state_ids();
+        let transitions = self.edges
+            .iter()
+            .filter(|edge| ids.contains_key(&edge.from))
+            .map(|edge| (ids[&edge.from], edge.condition, ids[&edge.to]))
+            .collect();
+        let accepting = ids.iter().filter(|&(state, _)| state.is_acceptor).map(|(_, &id)| id).collect();
+        (transitions, accepting)
+    }
+
+    // the shortest (then alphabetically first) string over alphabet that is
+    // not accepted, found by a breadth-first search for a rejecting state.
+    // missing transitions lead to an implicit dead state, which rejects
+    // everything.
+    pub fn counterexample(&self, alphabet: &BTreeSet<char>) -> Option<String> {
+        let walker = Walker::new(self);
+        let start = walker.start();
+        if !walker.accepts(start) {
+            return Some(String::new());
+        }
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(start);
+        queue.push_back((start, String::new()));
+        while let Some((state, path)) = queue.pop_front() {
+            for &c in alphabet {
+                let mut next = path.clone();
+                next.push(c);
+                match walker.step(state, c) {
+                    Some(to) if walker.accepts(to) => {
+                        if visited.insert(to) {
+                            queue.push_back((to, next));
+                        }
+                    }
+                    _ => return Some(next),
+                }
+            }
+        }
+        None
+    }
+
+    // how many strings of at most max_len chars are accepted, saturating at
+    // u64::MAX. counts paths by length over the transitions instead of
+    // enumerating strings; a Range transition stands for its chars without
+    // Char transitions and an Other transition for every char the state has
+    // neither for.
+    pub fn count_accepted(&self, max_len: usize) -> u64 {
+        let walker = Walker::new(self);
+        let mut counts: BTreeMap<&DFANode, u64> = BTreeMap::new();
+        counts.insert(walker.start(), 1);
+        let mut total: u64 = 0;
+        for len in 0..max_len + 1 {
+            for (&state, &count) in &counts {
+                if walker.accepts(state) {
+                    total = total.saturating_add(count);
+                }
+            }
+            if len == max_len {
+                break;
+            }
+            let mut next: BTreeMap<&DFANode, u64> = BTreeMap::new();
+            for (&state, &count) in &counts {
+                let transitions = match walker.transitions.get(state) {
+                    Some(transitions) => transitions,
+                    None => continue,
+                };
+                let chars: BTreeSet<char> = transitions.keys()
+                    .filter_map(|condition| match *condition {
+                        DFACondition::Char(c) => Some(c),
+                        _ => None,
+                    })
+                    .collect();
+                // the chars each Range transition takes
+                let range_len = |first: char, last: char| {
+                    ranges::len(first, last) - chars.range(first..=last).count() as u64
+                };
+                let mentioned = chars.len() as u64 + transitions.keys()
+                    .map(|condition| match *condition {
+                        DFACondition::Range(first, last) => range_len(first, last),
+                        _ => 0,
+                    })
+                    .sum::<u64>();
+                for (condition, &to) in transitions {
+                    let paths = match *condition {
+                        DFACondition::Char(_) => count,
+                        DFACondition::Range(first, last) => count.saturating_mul(range_len(first, last)),
+                        DFACondition::Other => count.saturating_mul(CHAR_COUNT - mentioned),
+                        DFACondition::StartAnchor | DFACondition::EndAnchor => continue,
+                    };
+                    let entry = next.entry(to).or_insert(0);
+                    *entry = entry.saturating_add(paths);
+                }
+            }
+            counts = next;
+        }
+        total
+    }
+
+    // the shortest (then alphabetically first) string accepted by self but
+    // not by other, if any
+    pub fn difference_witness(&self, other: &DFA) -> Option<String> {
+        self.product_witness(other, |lhs, rhs| lhs && !rhs)
+    }
+
+    // the shortest (then alphabetically first) string accepted by both self
+    // and other, if any
+    pub fn intersection_witness(&self, other: &DFA) -> Option<String> {
+        self.product_witness(other, |lhs, rhs| lhs && rhs)
+    }
+
+    // the shortest (then alphabetically first) string accepted by self for
+    // which found holds, given whether self and other accept it. chars
+    // neither DFA mentions all behave the same, so one of them stands in for
+    // the rest.
+    fn product_witness<F: Fn(bool, bool) -> bool>(&self, other: &DFA, found: F) -> Option<String> {
+        let walkers = [Walker::new(self), Walker::new(other)];
+        let alphabet = with_unmentioned(representatives(&[self, other]));
+
+        let accepts = |state: Option<&DFANode>, walker: &Walker| state.is_some_and(|state| walker.accepts(state));
+        let start = (Some(walkers[0].start()), Some(walkers[1].start()));
+        let mut visited = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(start);
+        queue.push_back((start, String::new()));
+        while let Some(((lhs, rhs), path)) = queue.pop_front() {
+            if found(accepts(lhs, &walkers[0]), accepts(rhs, &walkers[1])) {
+                return Some(path);
+            }
+            for &c in &alphabet {
+                let next = (lhs.and_then(|state| walkers[0].step(state, c)),
+                            rhs.and_then(|state| walkers[1].step(state, c)));
+                if next.0.is_some() && visited.insert(next) {
+                    let mut path = path.clone();
+                    path.push(c);
+                    queue.push_back((next, path));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn to_json(&self) -> String {
+        let ids = self.state_ids();
+        let mut states: Vec<usize> = ids.values().cloned().collect();
+        states.sort();
+        let mut accepting: Vec<usize> = ids.iter()
+            .filter(|&(state, _)| state.is_acceptor)
+            .map(|(_, &id)| id)
+            .collect();
+        accepting.sort();
+        let mut transitions: Vec<(usize, usize, DFACondition)> = self.edges
+            .iter()
+            .map(|edge| (ids[&edge.from], ids[&edge.to], edge.condition))
+            .collect();
+        transitions.sort();
+
+        let join = |v: Vec<String>| v.join(",");
+        format!("{{\"states\":[{}],\"transitions\":[{}],\"start\":{},\"accepting\":[{}]}}",
+                join(states.iter().map(|id| id.to_string()).collect()),
+                join(transitions.iter()
+                    .map(|&(from, to, condition)| {
+                        let condition = match condition {
+                            DFACondition::Char(c) => json::escape(&c.to_string()),
+                            DFACondition::Range(first, last) => {
+                                format!("{{\"range\":[{},{}]}}",
+                                        json::escape(&first.to_string()),
+                                        json::escape(&last.to_string()))
+                            }
+                            DFACondition::Other => "null".to_owned(),
+                            DFACondition::StartAnchor => "{\"anchor\":\"start\"}".to_owned(),
+                            DFACondition::EndAnchor => "{\"anchor\":\"end\"}".to_owned(),
+                        };
+                        format!("{{\"from\":{},\"to\":{},\"condition\":{}}}", from, to, condition)
+                    })
+                    .collect()),
+                ids[&self.start],
+                join(accepting.iter().map(|id| id.to_string()).collect()))
+    }
+
+    // reads back the output of to_json. state i becomes a DFANode holding the single node i,
+    // a null condition is an Other transition, {"range": [first, last]} a Range one
+    // and {"anchor": "start" or "end"} an anchor.
+    pub fn from_json(input: &str) -> Result<DFA, String> {
+        let value = json::parse(input)?;
+        let field = |name: &str| value.get(name).ok_or_else(|| format!("missing field `{}`", name));
+        let id = |value: &json::Value| value.as_usize().ok_or_else(|| "state ids must be non-negative integers".to_owned());
+
+        let mut accepting = BTreeSet::new();
+        for state in field("accepting")?.as_array().ok_or("`accepting` must be an array")? {
+            accepting.insert(id(state)?);
+        }
+        let state = |id: usize| {
+            DFANode {
+                nodes: [Node { id }].iter().cloned().collect(),
+                is_acceptor: accepting.contains(&id),
+            }
+        };
+
+        let mut dfa = DFA::new(state(id(field("start")?)?));
+        for transition in field("transitions")?.as_array().ok_or("`transitions` must be an array")? {
+            let member = |name: &str| transition.get(name).ok_or_else(|| format!("transition without `{}`", name));
+            let single = |value: &json::Value| {
+                let mut chars = value.as_str().ok_or("`condition` must be a string or null")?.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(c),
+                    _ => Err("`condition` must be a single character".to_owned()),
+                }
+            };
+            let condition = match *member("condition")? {
+                json::Value::Null => DFACondition::Other,
+                ref condition @ json::Value::Object(_) if condition.get("range").is_some() => {
+                    let range = match condition.get("range").and_then(|range| range.as_array()).map(|range| &range[..]) {
+                        Some([first, last]) => (single(first)?, single(last)?),
+                        _ => return Err("`range` must hold the first and the last character".to_owned()),
+                    };
+                    if range.0 > range.1 {
+                        return Err(format!("`range` from {} down to {}", range.0, range.1));
+                    }
+                    DFACondition::Range(range.0, range.1)
+                }
+                json::Value::Object(_) => {
+                    match member("condition")?.get("anchor").and_then(|anchor| anchor.as_str()) {
+                        Some("start") => DFACondition::StartAnchor,
+                        Some("end") => DFACondition::EndAnchor,
+                        _ => return Err("`anchor` must be \"start\" or \"end\"".to_owned()),
+                    }
+                }
+                ref condition => DFACondition::Char(single(condition)?),
+            };
+            dfa.edges.insert(DFAEdge {
+                condition,
+                from: state(id(member("from")?)?),
+                to: state(id(member("to")?)?),
+            });
+        }
+        Ok(dfa)
+    }
+
+    // an expression with the same language, by state elimination, see
+    // eliminate_states. an Other transition becomes the class of chars the
+    // state has no Char or Range transition for.
+    pub fn to_regex(&self) -> RegExpr {
+        let ids = self.state_ids();
+        let mut labels: BTreeMap<(usize, usize), RegExpr> = BTreeMap::new();
+        for (state, next) in self.transitions().into_iter().filter(|&(state, _)| ids.contains_key(state)) {
+            let chars: Vec<char> = next.keys()
+                .filter_map(|condition| match *condition {
+                    DFACondition::Char(c) => Some(c),
+                    _ => None,
+                })
+                .collect();
+            let intervals: Vec<(char, char)> = next.keys()
+                .filter_map(|condition| match *condition {
+                    DFACondition::Range(first, last) => Some((first, last)),
+                    _ => None,
+                })
+                .collect();
+            // the ranges could leave too many chars to list for a NegatedRange
+            let other = if intervals.is_empty() {
+                RegExpr::NegatedRange(chars.clone())
+            } else {
+                let mentioned = intervals.iter().cloned().chain(chars.iter().map(|&c| (c, c))).collect();
+                RegExpr::Ranges(ranges::complement(&ranges::normalize(mentioned)))
+            };
+            for (condition, to) in next {
+                let expr = match condition {
+                    DFACondition::Char(c) => RegExpr::Character(c),
+                    DFACondition::Range(first, last) => RegExpr::Ranges(vec![(first, last)]),
+                    DFACondition::Other => other.clone(),
+                    DFACondition::StartAnchor => RegExpr::StartAnchor,
+                    DFACondition::EndAnchor => RegExpr::EndAnchor,
+                };
+                add_label(&mut labels, ids[state], ids[to], expr);
+            }
+        }
+        let acceptors = ids.iter().filter(|&(state, _)| state.is_acceptor).map(|(_, &id)| id).collect();
+        eliminate_states(ids.len(), ids[&self.start], &acceptors, labels)
+    }
+
+    // DFA accepting exactly the given strings, shaped as a trie over them:
+    // strings sharing a prefix share the states for it
+    pub fn from_literals<S: AsRef<str>>(literals: &[S]) -> DFA {
+        let mut alloc = NodeAllocator::new();
+        let root = Node::new(&mut alloc);
+        let mut children: BTreeMap<(Node, char), Node> = BTreeMap::new();
+        let mut accepting: BTreeSet<Node> = BTreeSet::new();
+        for literal in literals {
+            let mut current = root;
+            for c in literal.as_ref().chars() {
+                current = *children.entry((current, c)).or_insert_with(|| Node::new(&mut alloc));
+            }
+            accepting.insert(current);
+        }
+
+        let state = |node: Node| {
+            DFANode {
+                nodes: [node].iter().cloned().collect(),
+                is_acceptor: accepting.contains(&node),
+            }
+        };
+        let mut dfa = DFA::new(state(root));
+        for (&(from, c), &to) in &children {
+            dfa.edges.insert(DFAEdge {
+                condition: DFACondition::Char(c),
+                from: state(from),
+                to: state(to),
+            });
+        }
+        dfa
+    }
+
+    // DFA accepting any string accepted by one of dfas, built as the product
+    // automaton: a state records where each DFA is (None once it has rejected)
+    // and accepts if any of them does.
+    pub fn union(dfas: &[&DFA]) -> DFA {
+        let transitions: Vec<_> = dfas.iter().map(|dfa| dfa.transitions()).collect();
+        let mut alloc = NodeAllocator::new();
+        let mut new_state = |tuple: &[Option<&DFANode>]| {
+            DFANode {
+                nodes: [Node::new(&mut alloc)].iter().cloned().collect(),
+                is_acceptor: tuple.iter().any(|state| state.is_some_and(|state| state.is_acceptor)),
+            }
+        };
+
+        let start: Vec<Option<&DFANode>> = dfas.iter().map(|dfa| Some(&dfa.start)).collect();
+        let mut states: BTreeMap<Vec<Option<&DFANode>>, DFANode> = BTreeMap::new();
+        states.insert(start.clone(), new_state(&start));
+        let mut ret = DFA::new(states[&start].clone());
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        while let Some(tuple) = queue.pop_front() {
+            let outgoing: Vec<Option<&BTreeMap<DFACondition, &DFANode>>> = tuple.iter()
+                .zip(&transitions)
+                .map(|(state, transitions)| state.and_then(|state| transitions.get(state)))
+                .collect();
+            let mut conditions: BTreeSet<DFACondition> = outgoing.iter()
+                .flat_map(|next| next.iter().flat_map(|next| next.keys().cloned()))
+                .collect();
+            // ranges of different DFAs may overlap, so they are cut where any
+            // of them starts or ends, and around the chars with their own transition
+            let chars: BTreeSet<char> = conditions.iter()
+                .filter_map(|condition| match *condition {
+                    DFACondition::Char(c) => Some(c),
+                    _ => None,
+                })
+                .collect();
+            let intervals: Vec<(char, char)> = conditions.iter()
+                .filter_map(|condition| match *condition {
+                    DFACondition::Range(first, last) => Some((first, last)),
+                    _ => None,
+                })
+                .collect();
+            conditions.retain(|condition| !matches!(condition, DFACondition::Range(..)));
+            conditions.extend(ranges::split(&intervals, &chars).into_iter().map(|(first, last)| {
+                DFACondition::Range(first, last)
+            }));
+            for condition in conditions {
+                let next: Vec<Option<&DFANode>> = tuple.iter()
+                    .zip(&outgoing)
+                    .map(|(&state, next)| match condition {
+                        DFACondition::Char(c) | DFACondition::Range(c, _) => next.and_then(|next| step(next, c)),
+                        DFACondition::Other => next.and_then(|next| next.get(&condition).cloned()),
+                        // anchors consume nothing, the DFAs without one stay where they are
+                        DFACondition::StartAnchor | DFACondition::EndAnchor => {
+                            next.and_then(|next| next.get(&condition).cloned()).or(state)
+                        }
+                    })
+                    .collect();
+                if !states.contains_key(&next) {
+                    let state = new_state(&next);
+                    states.insert(next.clone(), state);
+                    queue.push_back(next.clone());
+                }
+                ret.edges.insert(DFAEdge {
+                    condition,
+                    from: states[&tuple].clone(),
+                    to: states[&next].clone(),
+                });
+            }
+        }
+        ret
+    }
+
+    // label of every state in the given format
+    pub fn labels(&self, format: LabelFormat) -> BTreeMap<&DFANode, String> {
+        match format {
+            LabelFormat::Set => {
+                self.states().into_iter().map(|state| (state, state.pretty_name())).collect()
+            }
+            LabelFormat::Id => {
+                self.state_ids().into_iter().map(|(state, id)| (state, id.to_string())).collect()
+            }
+        }
+    }
+
     pub fn dotty_print<W: Write + ?Sized>(&self, writer: &mut W) {
+        self.dotty_print_with(writer, LabelFormat::Set)
+    }
+
+    pub fn dotty_print_with<W: Write + ?Sized>(&self, writer: &mut W, format: LabelFormat) {
+        let labels = self.labels(format);
         writeln!(writer, "digraph g{{").unwrap();
 
         for edge in &self.edges {
             writeln!(writer,
                      "\t{} -> {} [ label = \"{}\" ];",
-                     edge.from.pretty_name(),
-                     edge.to.pretty_name(),
+                     labels[&edge.from],
+                     labels[&edge.to],
                      edge.condition)
                 .unwrap();
             if edge.from.is_acceptor {
                 writeln!(writer,
                          "\t{} [ style = \"bold\" ];",
-                         edge.from.pretty_name())
+                         labels[&edge.from])
                     .unwrap();
             }
             if edge.to.is_acceptor {
-                writeln!(writer, "\t{} [ style = \"bold\" ];", edge.to.pretty_name()).unwrap();
+                writeln!(writer, "\t{} [ style = \"bold\" ];", labels[&edge.to]).unwrap();
             }
         }
 
+        // trap states only clutter the layout, so they are kept out of the way
+        let dead = self.dead_states();
+        if !dead.is_empty() {
+            writeln!(writer, "\tsubgraph cluster_dead {{").unwrap();
+            writeln!(writer, "\t\tlabel = \"dead\";").unwrap();
+            writeln!(writer, "\t\tstyle = \"dashed\";").unwrap();
+            for state in dead {
+                writeln!(writer, "\t\t{};", labels[state]).unwrap();
+            }
+            writeln!(writer, "\t}}").unwrap();
+        }
+
         writeln!(writer, "}}").unwrap();
     }
 }
 
+impl PartialEq for DFA {
+    fn eq(&self, other: &DFA) -> bool {
+        self.canonical_form() == other.canonical_form()
+    }
+}
+
+impl Eq for DFA {}
+
 impl Graph {
     fn new(start: Node) -> Graph {
         Graph {
             start: start,
             edges: BTreeSet::new(),
             acceptors: BTreeSet::new(),
+            groups: BTreeMap::new(),
         }
     }
 
-    fn add_edge(&mut self, condition: Option<char>, from: Node, to: Node) {
+    fn add_edge(&mut self, condition: Condition, from: Node, to: Node) {
         self.edges.insert(Edge {
             condition: condition,
             from: from,
@@ -133,20 +974,12 @@ impl Graph {
         }
 
         self.traverse(&mut |edge| {
-                          if let Some(c) = edge.condition {
-                              writeln!(writer,
-                                       "\t{} -> {} [ label = \"{}\" ];",
-                                       edge.from.id,
-                                       edge.to.id,
-                                       c)
-                                  .unwrap();
-                          } else {
-                              writeln!(writer,
-                                       "\t{} -> {} [ label = \"ε\" ];",
-                                       edge.from.id,
-                                       edge.to.id)
-                                  .unwrap();
-                          }
+                          writeln!(writer,
+                                   "\t{} -> {} [ label = \"{}\" ];",
+                                   edge.from.id,
+                                   edge.to.id,
+                                   edge.condition)
+                              .unwrap();
                       },
                       &self.start,
                       &mut HashSet::new());
@@ -155,19 +988,314 @@ impl Graph {
 
     }
 
-    fn traverse<F: FnMut(&Edge)>(&self, f: &mut F, current: &Node, visited: &mut HashSet<Node>) {
-        if visited.contains(current) {
-            return;
+    // None if an edge takes a class or an anchor, which a table of single
+    // chars has no room for
+    pub fn to_nfa_table(&self) -> Option<NFATable> {
+        let index = self.node_index();
+        let mut edges = vec![vec![]; index.len()];
+        for edge in &self.edges {
+            let label = match edge.condition {
+                Condition::Epsilon => None,
+                Condition::Char(c) => Some(c),
+                _ => return None,
+            };
+            edges[index[&edge.from]].push((label, index[&edge.to]));
         }
+        Some(NFATable {
+            start: index[&self.start],
+            edges,
+            acceptors: self.acceptors.iter().map(|node| index[node]).collect(),
+            groups: self.groups.iter().map(|(node, &boundary)| (index[node], boundary)).collect(),
+        })
+    }
 
-        visited.insert(*current);
+    pub fn from_nfa_table(table: &NFATable, alloc: &mut NodeAllocator) -> Graph {
+        let nodes: Vec<Node> = (0..table.edges.len()).map(|_| Node::new(alloc)).collect();
+        let mut graph = Graph::new(nodes[table.start]);
+        for (from, edges) in table.edges.iter().enumerate() {
+            for &(label, to) in edges {
+                let condition = label.map_or(Condition::Epsilon, Condition::Char);
+                graph.add_edge(condition, nodes[from], nodes[to]);
+            }
+        }
+        graph.acceptors = table.acceptors.iter().map(|&i| nodes[i]).collect();
+        graph.groups = table.groups.iter().map(|(&i, &boundary)| (nodes[i], boundary)).collect();
+        graph
+    }
 
-        for edge in self.edges.iter().filter(|edge| edge.from == *current) {
-            f(edge);
+    // the nodes numbered from 0 in order, as in an NFATable
+    fn node_index(&self) -> HashMap<Node, usize> {
+        let mut nodes: BTreeSet<Node> = self.acceptors.clone();
+        nodes.insert(self.start);
+        for edge in &self.edges {
+            nodes.insert(edge.from);
+            nodes.insert(edge.to);
+        }
+        nodes.iter().enumerate().map(|(i, node)| (*node, i)).collect()
+    }
+
+    // drops every epsilon edge whose target stays reachable from its source
+    // through the remaining epsilon edges. epsilon closures, and so the
+    // language and the DFA, are unchanged; the closures just get cheaper.
+    pub fn reduce_epsilon_edges(&mut self) {
+        let mut epsilons: BTreeMap<Node, BTreeSet<Node>> = BTreeMap::new();
+        for edge in self.edges.iter().filter(|edge| edge.condition == Condition::Epsilon) {
+            epsilons.entry(edge.from).or_default().insert(edge.to);
+        }
+        let candidates: Vec<(Node, Node)> = epsilons.iter()
+            .flat_map(|(&from, targets)| targets.iter().map(move |&to| (from, to)))
+            .collect();
+        for (from, to) in candidates {
+            epsilons.get_mut(&from).unwrap().remove(&to);
+            let mut reached = BTreeSet::new();
+            let mut pending = vec![from];
+            while let Some(node) = pending.pop() {
+                if node == to {
+                    break;
+                }
+                for &next in epsilons.get(&node).into_iter().flatten() {
+                    if reached.insert(next) {
+                        pending.push(next);
+                    }
+                }
+            }
+            if !reached.contains(&to) && from != to {
+                epsilons.get_mut(&from).unwrap().insert(to);
+            }
         }
+        self.edges.retain(|edge| {
+            edge.condition != Condition::Epsilon || epsilons[&edge.from].contains(&edge.to)
+        });
+    }
 
-        for edge in self.edges.iter().filter(|edge| edge.from == *current) {
-            self.traverse(f, &edge.to, visited);
+    // an expression with the same language, by state elimination, see
+    // eliminate_states
+    pub fn to_regex(&self) -> RegExpr {
+        let index = self.node_index();
+        let mut labels: BTreeMap<(usize, usize), RegExpr> = BTreeMap::new();
+        for edge in &self.edges {
+            let expr = match edge.condition {
+                Condition::Epsilon => RegExpr::Sequence(vec![]),
+                Condition::Char(c) => RegExpr::Character(c),
+                Condition::Any => RegExpr::Any,
+                Condition::Except(ref set) => RegExpr::NegatedRange(set.iter().cloned().collect()),
+                Condition::Ranges(ref ranges) => RegExpr::Ranges(ranges.clone()),
+                Condition::StartAnchor => RegExpr::StartAnchor,
+                Condition::EndAnchor => RegExpr::EndAnchor,
+            };
+            add_label(&mut labels, index[&edge.from], index[&edge.to], expr);
+        }
+        let acceptors = self.acceptors.iter().map(|node| index[node]).collect();
+        eliminate_states(index.len(), index[&self.start], &acceptors, labels)
+    }
+
+    // number of nodes with an edge, plus the start and the acceptors
+    pub fn node_count(&self) -> usize {
+        let mut nodes: BTreeSet<Node> = self.acceptors.clone();
+        nodes.insert(self.start);
+        for edge in &self.edges {
+            nodes.insert(edge.from);
+            nodes.insert(edge.to);
+        }
+        nodes.len()
+    }
+
+    // a node without edges that the graph does not use
+    fn unused_node(&self) -> Node {
+        let ids = self.edges.iter().flat_map(|edge| vec![edge.from.id, edge.to.id]);
+        let id = ids.chain(self.acceptors.iter().map(|node| node.id)).fold(self.start.id, usize::max);
+        Node { id: id + 1 }
+    }
+
+    // whether the empty input is accepted. both `^` and `$` hold there, so
+    // they can be crossed in any order, as in `$^`.
+    pub fn accepts_empty(&self) -> bool {
+        let start = [self.start].iter().cloned().collect();
+        let end = reachable_through(self, &start, &[Condition::Epsilon, Condition::StartAnchor, Condition::EndAnchor]);
+        end.intersection(&self.acceptors).next().is_some()
+    }
+
+    // the nodes active before any input is read, past any `^`
+    pub fn start_nodes(&self) -> BTreeSet<Node> {
+        let start = [self.start].iter().cloned().collect();
+        reachable_through(self, &start, &[Condition::Epsilon, Condition::StartAnchor])
+    }
+
+    // the nodes active after reading c from the active nodes
+    pub fn step(&self, nodes: &BTreeSet<Node>, c: char) -> BTreeSet<Node> {
+        let next = self.edges
+                       .iter()
+                       .filter(|edge| nodes.contains(&edge.from) && edge.condition.matches(c))
+                       .map(|edge| edge.to)
+                       .collect();
+        reachable_through_epsilon(self, &next)
+    }
+
+    // whether the active nodes accept when the input ends, possibly through `$`
+    pub fn accepts_at_end(&self, nodes: &BTreeSet<Node>) -> bool {
+        let end = reachable_through(self, nodes, &[Condition::Epsilon, Condition::EndAnchor]);
+        end.intersection(&self.acceptors).next().is_some()
+    }
+
+    // the highest capture group number, 0 without groups
+    pub fn group_count(&self) -> usize {
+        self.groups
+            .values()
+            .map(|&boundary| match boundary {
+                GroupBoundary::Open(group) | GroupBoundary::Close(group) => group,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    // the byte spans of the capture groups for a match of all of s, indexed by
+    // group number, with the whole match at 0. groups that took no part in the
+    // match are None, and a group matched repeatedly reports its last span.
+    //
+    // the simulation keeps its threads in priority order. epsilon edges are
+    // tried in the order of their targets, which build_nfa allocates left
+    // alternatives before right ones and loops before their exits, so
+    // ambiguous patterns resolve like a backtracking matcher would.
+    pub fn captures(&self, s: &str) -> Option<GroupSpans> {
+        self.captures_at(s, (0, s.len()))
+    }
+
+    // like captures, for a match of the span of haystack between the byte
+    // offsets start and end. the spans are offsets into haystack, and `^`
+    // and `$` only hold at its ends, so the match can be one that find found.
+    pub fn captures_at(&self, haystack: &str, span: (usize, usize)) -> Option<GroupSpans> {
+        self.captures_spans(haystack, Some(span)).pop().unwrap()
+    }
+
+    // captures_at for each of spans in turn. the edges are sorted, and the
+    // buffers of the simulation allocated, once for all of them.
+    pub fn captures_spans<I>(&self, haystack: &str, spans: I) -> Vec<Option<GroupSpans>>
+        where I: IntoIterator<Item = (usize, usize)>
+    {
+        let groups = self.group_count();
+        let mut successors: BTreeMap<Node, Vec<&Edge>> = BTreeMap::new();
+        for edge in &self.edges {
+            successors.entry(edge.from).or_default().push(edge);
+        }
+        for edges in successors.values_mut() {
+            edges.sort_by_key(|edge| edge.to);
+        }
+        // the threads record char steps, which become byte offsets at the end
+        let positions = PositionMap::new(haystack);
+        let last_step = positions.last_step();
+
+        // moves into to the nodes reached from the threads in from without
+        // consuming input at step, each by its first path in priority order
+        let mut seen = HashSet::new();
+        let mut pending: Vec<(Node, Vec<Option<usize>>)> = vec![];
+        let mut close = |from: &mut Vec<(Node, Vec<Option<usize>>)>, step: usize, to: &mut Vec<(Node, Vec<Option<usize>>)>| {
+            seen.clear();
+            pending.extend(from.drain(..).rev());
+            while let Some((node, mut slots)) = pending.pop() {
+                if !seen.insert(node) {
+                    continue;
+                }
+                match self.groups.get(&node) {
+                    Some(&GroupBoundary::Open(group)) => slots[group * 2] = Some(step),
+                    Some(&GroupBoundary::Close(group)) => slots[group * 2 + 1] = Some(step),
+                    None => {}
+                }
+                for edge in successors.get(&node).into_iter().flatten().rev() {
+                    let zero_width = match edge.condition {
+                        Condition::Epsilon => true,
+                        Condition::StartAnchor => step == 0,
+                        Condition::EndAnchor => step == last_step,
+                        _ => false,
+                    };
+                    if zero_width && !seen.contains(&edge.to) {
+                        pending.push((edge.to, slots.clone()));
+                    }
+                }
+                to.push((node, slots));
+            }
+        };
+
+        let mut ret = vec![];
+        let mut threads = vec![];
+        let mut next = vec![];
+        for span in spans {
+            let (start, end) = positions.steps(span);
+            threads.clear();
+            next.push((self.start, vec![None; groups * 2 + 2]));
+            close(&mut next, start, &mut threads);
+            for (i, c) in haystack[span.0..span.1].chars().enumerate() {
+                for (node, slots) in threads.drain(..) {
+                    for edge in successors.get(&node).into_iter().flatten() {
+                        if edge.condition.matches(c) {
+                            next.push((edge.to, slots.clone()));
+                        }
+                    }
+                }
+                close(&mut next, start + i + 1, &mut threads);
+                if threads.is_empty() {
+                    break;
+                }
+            }
+            let accepted = threads.drain(..).find(|(node, _)| self.acceptors.contains(node));
+            ret.push(accepted.map(|(_, mut slots)| {
+                slots[0] = Some(start);
+                slots[1] = Some(end);
+                slots.chunks(2)
+                    .map(|span| match (span[0], span[1]) {
+                        (Some(start), Some(end)) => Some(positions.bytes((start, end))),
+                        _ => None,
+                    })
+                    .collect()
+            }));
+        }
+        ret
+    }
+
+    // strongly connected components (Tarjan), in reverse topological order.
+    // every node ends up in exactly one component; a component with more than
+    // one node, or a node with an edge to itself, is a loop of the NFA.
+    pub fn sccs(&self) -> Vec<Vec<Node>> {
+        let mut successors: BTreeMap<Node, Vec<Node>> = BTreeMap::new();
+        successors.entry(self.start).or_default();
+        for edge in &self.edges {
+            successors.entry(edge.from).or_default().push(edge.to);
+            successors.entry(edge.to).or_default();
+        }
+
+        let mut tarjan = Tarjan {
+            successors: &successors,
+            index: HashMap::new(),
+            lowlink: HashMap::new(),
+            stack: vec![],
+            on_stack: HashSet::new(),
+            components: vec![],
+        };
+        for node in successors.keys() {
+            if !tarjan.index.contains_key(node) {
+                tarjan.visit(*node);
+            }
+        }
+        tarjan.components
+    }
+
+    // calls f once on every edge reachable from current. uses an explicit
+    // stack, so long chains of nodes cannot overflow the call stack.
+    fn traverse<F: FnMut(&Edge)>(&self, f: &mut F, current: &Node, visited: &mut HashSet<Node>) {
+        let mut successors: HashMap<Node, Vec<&Edge>> = HashMap::new();
+        for edge in &self.edges {
+            successors.entry(edge.from).or_default().push(edge);
+        }
+
+        let mut stack = vec![*current];
+        while let Some(current) = stack.pop() {
+            if !visited.insert(current) {
+                continue;
+            }
+
+            for &edge in successors.get(&current).into_iter().flatten() {
+                f(edge);
+                stack.push(edge.to);
+            }
         }
     }
     // fn traverse_node<F: FnMut(&Node)>(&self,
@@ -189,37 +1317,226 @@ impl Graph {
     //
 }
 
+impl CountedGraph {
+    pub fn node_count(&self) -> usize {
+        self.graph.node_count()
+    }
+
+    // like Graph::accepts_empty, following the counters
+    pub fn accepts_empty(&self) -> bool {
+        let end = self.close(self.start_states(), &[Condition::Epsilon, Condition::StartAnchor, Condition::EndAnchor]);
+        end.iter().any(|&(node, _)| self.graph.acceptors.contains(&node))
+    }
+
+    // the nodes active before any input is read, past any `^`, with the
+    // counts they were reached with
+    pub fn start_states(&self) -> BTreeSet<(Node, Counts)> {
+        let start = self.enter(self.graph.start, &Counts::new()).into_iter().collect();
+        self.close(start, &[Condition::Epsilon, Condition::StartAnchor])
+    }
+
+    // the nodes active after reading c from the active ones
+    pub fn step(&self, states: &BTreeSet<(Node, Counts)>, c: char) -> BTreeSet<(Node, Counts)> {
+        let mut counts: BTreeMap<Node, Vec<&Counts>> = BTreeMap::new();
+        for &(node, ref node_counts) in states {
+            counts.entry(node).or_default().push(node_counts);
+        }
+        let next = self.graph
+                       .edges
+                       .iter()
+                       .filter(|edge| counts.contains_key(&edge.from) && edge.condition.matches(c))
+                       .flat_map(|edge| counts[&edge.from].iter().filter_map(move |&counts| self.enter(edge.to, counts)))
+                       .collect();
+        self.close(next, &[Condition::Epsilon])
+    }
+
+    // whether the active nodes accept when the input ends, possibly through `$`
+    pub fn accepts_at_end(&self, states: &BTreeSet<(Node, Counts)>) -> bool {
+        let end = self.close(states.clone(), &[Condition::Epsilon, Condition::EndAnchor]);
+        end.iter().any(|&(node, _)| self.graph.acceptors.contains(&node))
+    }
+
+    // node with the counts after entering it, None if its counter forbids that
+    fn enter(&self, node: Node, counts: &Counts) -> Option<(Node, Counts)> {
+        let mut counts = counts.clone();
+        let count = |hub: Node| counts.get(&hub).cloned().unwrap_or(0);
+        match self.counters.get(&node) {
+            Some(&CounterOp::Below(hub, bound)) if count(hub) >= bound => return None,
+            Some(&CounterOp::Exit(hub, bound)) if count(hub) < bound => return None,
+            Some(&CounterOp::Exit(hub, _)) => {
+                counts.remove(&hub);
+            }
+            Some(&CounterOp::Increment(hub, bound)) => {
+                let count = counts.entry(hub).or_insert(0);
+                *count = cmp::min(*count + 1, bound);
+            }
+            _ => {}
+        }
+        Some((node, counts))
+    }
+
+    // like reachable_through, keeping track of the counts
+    fn close(&self, states: BTreeSet<(Node, Counts)>, conditions: &[Condition]) -> BTreeSet<(Node, Counts)> {
+        let mut ret = states.clone();
+        let mut pending: Vec<(Node, Counts)> = states.into_iter().collect();
+        while let Some((node, counts)) = pending.pop() {
+            for edge in self.graph.edges.iter().filter(|edge| edge.from == node && conditions.contains(&edge.condition)) {
+                if let Some(state) = self.enter(edge.to, &counts) {
+                    if !ret.contains(&state) {
+                        ret.insert(state.clone());
+                        pending.push(state);
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+struct Tarjan<'a> {
+    successors: &'a BTreeMap<Node, Vec<Node>>,
+    index: HashMap<Node, usize>,
+    lowlink: HashMap<Node, usize>,
+    stack: Vec<Node>,
+    on_stack: HashSet<Node>,
+    components: Vec<Vec<Node>>,
+}
+
+impl<'a> Tarjan<'a> {
+    // the depth-first search from root. it keeps its own stack of the nodes
+    // being visited, with the position of the next successor to look at, so
+    // long chains of nodes cannot overflow the call stack.
+    fn visit(&mut self, root: Node) {
+        self.enter(root);
+        let mut visiting = vec![(root, 0)];
+        while let Some((node, i)) = visiting.pop() {
+            if let Some(&next) = self.successors[&node].get(i) {
+                visiting.push((node, i + 1));
+                if !self.index.contains_key(&next) {
+                    self.enter(next);
+                    visiting.push((next, 0));
+                } else if self.on_stack.contains(&next) {
+                    let lowlink = self.lowlink[&node].min(self.index[&next]);
+                    self.lowlink.insert(node, lowlink);
+                }
+                continue;
+            }
+
+            // every successor is done, node returns to the one it was reached from
+            if let Some(&(parent, _)) = visiting.last() {
+                let lowlink = self.lowlink[&parent].min(self.lowlink[&node]);
+                self.lowlink.insert(parent, lowlink);
+            }
+            if self.lowlink[&node] == self.index[&node] {
+                let mut component = vec![];
+                loop {
+                    let member = self.stack.pop().unwrap();
+                    self.on_stack.remove(&member);
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                self.components.push(component);
+            }
+        }
+    }
+
+    fn enter(&mut self, node: Node) {
+        let index = self.index.len();
+        self.index.insert(node, index);
+        self.lowlink.insert(node, index);
+        self.stack.push(node);
+        self.on_stack.insert(node);
+    }
+}
+
 pub fn build_nfa(expr: &RegExpr, alloc: &mut NodeAllocator) -> Graph {
+    build(expr, alloc, &mut None)
+}
+
+// like build_nfa, but a repetition counted up to at least threshold, like
+// a{1000} or a{1000,} for a threshold of 1000, is built once with a counter
+// instead of once per count. the graph stays small, but only CountedGraph's
+// own simulation knows about the counters, see CountedNfaEngine.
+pub fn build_nfa_with_counters(expr: &RegExpr, alloc: &mut NodeAllocator, threshold: usize) -> CountedGraph {
+    let mut counting = Some(Counting {
+        threshold,
+        counters: BTreeMap::new(),
+    });
+    let graph = build(expr, alloc, &mut counting);
+    CountedGraph {
+        graph,
+        counters: counting.unwrap().counters,
+    }
+}
+
+fn build(expr: &RegExpr, alloc: &mut NodeAllocator, counting: &mut Option<Counting>) -> Graph {
     match *expr {
         RegExpr::Character(c) => {
             let start = Node::new(alloc);
             let end = Node::new(alloc);
             let mut graph = Graph::new(start);
-            graph.add_edge(Some(c), start, end);
+            graph.add_edge(Condition::Char(c), start, end);
             graph.acceptors.insert(end);
             graph
         }
-        RegExpr::Sequence(ref v) => {
+        RegExpr::Any => {
             let start = Node::new(alloc);
-            let nfas: Vec<Graph>;
-            {
-                nfas = v.iter().map(|e| build_nfa(e, alloc)).collect();
-            }
             let end = Node::new(alloc);
-
+            let mut graph = Graph::new(start);
+            graph.add_edge(Condition::Any, start, end);
+            graph.acceptors.insert(end);
+            graph
+        }
+        RegExpr::StartAnchor | RegExpr::EndAnchor => {
+            let condition = match *expr {
+                RegExpr::StartAnchor => Condition::StartAnchor,
+                _ => Condition::EndAnchor,
+            };
+            let start = Node::new(alloc);
+            let end = Node::new(alloc);
+            let mut graph = Graph::new(start);
+            graph.add_edge(condition, start, end);
+            graph.acceptors.insert(end);
+            graph
+        }
+        RegExpr::NegatedRange(ref range) => {
+            let start = Node::new(alloc);
+            let end = Node::new(alloc);
+            let mut graph = Graph::new(start);
+            graph.add_edge(Condition::Except(range.iter().cloned().collect()), start, end);
+            graph.acceptors.insert(end);
+            graph
+        }
+        RegExpr::Ranges(ref ranges) => {
+            let start = Node::new(alloc);
+            let end = Node::new(alloc);
+            let mut graph = Graph::new(start);
+            graph.add_edge(Condition::Ranges(ranges.clone()), start, end);
+            graph.acceptors.insert(end);
+            graph
+        }
+        RegExpr::Sequence(ref v) => {
+            let start = Node::new(alloc);
             let mut current_end: BTreeSet<Node> = [start].iter().cloned().collect();
             let mut ret = Graph::new(start);
 
-            for nfa in nfas {
+            // each element is merged as soon as it is built, so only one
+            // sub-NFA is alive besides the result at any time
+            for e in v {
+                let nfa = build(e, alloc, counting);
                 ret.edges.extend(nfa.edges);
+                ret.groups.extend(nfa.groups);
                 for acceptor in current_end {
-                    ret.add_edge(None, acceptor, nfa.start);
+                    ret.add_edge(Condition::Epsilon, acceptor, nfa.start);
                 }
                 current_end = nfa.acceptors;
             }
 
+            let end = Node::new(alloc);
             for acceptor in current_end {
-                ret.add_edge(None, acceptor, end)
+                ret.add_edge(Condition::Epsilon, acceptor, end)
             }
             ret.acceptors.insert(end);
             ret
@@ -241,34 +1558,38 @@ pub fn build_nfa(expr: &RegExpr, alloc: &mut NodeAllocator) -> Graph {
         }
         RegExpr::Branch(ref lhs, ref rhs) => {
             use std::iter::Iterator;
-            let lhs = build_nfa(&lhs, alloc);
-            let rhs = build_nfa(&rhs, alloc);
+            // lhs has to get the lower node ids, Graph::captures prefers
+            // alternatives by them. the parser nests `a|b|c` as ((a|b)|c),
+            // so all alternatives are then tried left to right.
+            let lhs = build(lhs, alloc, counting);
+            let rhs = build(rhs, alloc, counting);
             let start = Node::new(alloc);
             let end = Node::new(alloc);
 
             let mut graph = Graph::new(start);
             graph.acceptors = [end].iter().map(|node| *node).collect();
             graph.edges = lhs.edges.union(&rhs.edges).cloned().collect();
+            graph.groups = lhs.groups.iter().chain(&rhs.groups).map(|(&node, &boundary)| (node, boundary)).collect();
             graph.edges.insert(Edge {
-                condition: None,
+                condition: Condition::Epsilon,
                 from: start,
                 to: lhs.start,
             });
             graph.edges.insert(Edge {
-                condition: None,
+                condition: Condition::Epsilon,
                 from: start,
                 to: rhs.start,
             });
             graph.edges.extend(lhs.acceptors.iter().map(|acceptor| {
                 Edge {
-                    condition: None,
+                    condition: Condition::Epsilon,
                     from: *acceptor,
                     to: end,
                 }
             }));
             graph.edges.extend(rhs.acceptors.iter().map(|acceptor| {
                 Edge {
-                    condition: None,
+                    condition: Condition::Epsilon,
                     from: *acceptor,
                     to: end,
                 }
@@ -285,18 +1606,19 @@ pub fn build_nfa(expr: &RegExpr, alloc: &mut NodeAllocator) -> Graph {
                 edges: range.iter()
                             .map(|&c| {
                                 Edge {
-                                    condition: Some(c),
+                                    condition: Condition::Char(c),
                                     from: start,
                                     to: end,
                                 }
                             })
                             .collect(),
                 acceptors: [end].iter().cloned().collect(),
+                groups: BTreeMap::new(),
             }
 
         }
         RegExpr::Repeation(ref expr) => {
-            let mut graph = build_nfa(&expr, alloc);
+            let mut graph = build(expr, alloc, counting);
             graph.acceptors.insert(graph.start);
             let new_edges: Vec<Edge>;
             {
@@ -304,12 +1626,12 @@ pub fn build_nfa(expr: &RegExpr, alloc: &mut NodeAllocator) -> Graph {
                                  .iter()
                                  .flat_map(|acceptor| {
                                      vec![Edge {
-                                              condition: None,
+                                              condition: Condition::Epsilon,
                                               from: graph.start,
                                               to: *acceptor,
                                           },
                                           Edge {
-                                              condition: None,
+                                              condition: Condition::Epsilon,
                                               from: *acceptor,
                                               to: graph.start,
                                           }]
@@ -320,24 +1642,214 @@ pub fn build_nfa(expr: &RegExpr, alloc: &mut NodeAllocator) -> Graph {
             graph
 
         }
+        RegExpr::Plus(ref expr) => {
+            // like Repeation, but the start node is not accepting,
+            // so at least one iteration is required
+            let mut graph = build(expr, alloc, counting);
+            let new_edges: Vec<Edge> = graph.acceptors
+                                            .iter()
+                                            .map(|acceptor| {
+                                                Edge {
+                                                    condition: Condition::Epsilon,
+                                                    from: *acceptor,
+                                                    to: graph.start,
+                                                }
+                                            })
+                                            .collect();
+            graph.edges.extend(new_edges);
+            graph
+        }
+        RegExpr::Repeat { ref expr, min, max } if counting.as_ref().is_some_and(|counting| max.unwrap_or(min) >= counting.threshold) => {
+            // a single copy looping back to the hub through a node counting
+            // the iterations, see CounterOp. like for groups, the counting
+            // sits inside plain start and end nodes, where the count is 0.
+            let inner = build(expr, alloc, counting);
+            let start = Node::new(alloc);
+            let hub = Node::new(alloc);
+            let increment = Node::new(alloc);
+            let exit = Node::new(alloc);
+            let end = Node::new(alloc);
+
+            let counters = &mut counting.as_mut().unwrap().counters;
+
+            let mut graph = Graph::new(start);
+            graph.edges = inner.edges;
+            graph.groups = inner.groups;
+            graph.add_edge(Condition::Epsilon, start, hub);
+            match max {
+                Some(max) => {
+                    let below = Node::new(alloc);
+                    graph.add_edge(Condition::Epsilon, hub, below);
+                    graph.add_edge(Condition::Epsilon, below, inner.start);
+                    counters.insert(below, CounterOp::Below(hub, max));
+                }
+                None => graph.add_edge(Condition::Epsilon, hub, inner.start),
+            }
+            for acceptor in inner.acceptors {
+                graph.add_edge(Condition::Epsilon, acceptor, increment);
+            }
+            graph.add_edge(Condition::Epsilon, increment, hub);
+            graph.add_edge(Condition::Epsilon, hub, exit);
+            graph.add_edge(Condition::Epsilon, exit, end);
+            counters.insert(increment, CounterOp::Increment(hub, max.unwrap_or(min)));
+            counters.insert(exit, CounterOp::Exit(hub, min));
+            graph.acceptors.insert(end);
+            graph
+        }
+        RegExpr::Repeat { ref expr, min, max } => {
+            // min mandatory copies, then either a star or max - min nested
+            // optional copies, so a{1,3} is built like a(a(a)?)?
+            let tail = match max {
+                None => RegExpr::Repeation(expr.clone()),
+                Some(max) => {
+                    (min..max).fold(RegExpr::Sequence(vec![]), |tail, _| {
+                        RegExpr::Branch(Box::new(RegExpr::Sequence(vec![(**expr).clone(), tail])),
+                                        Box::new(RegExpr::Sequence(vec![])))
+                    })
+                }
+            };
+            let mut v: Vec<RegExpr> = (0..min).map(|_| (**expr).clone()).collect();
+            v.push(tail);
+            build(&RegExpr::Sequence(v), alloc, counting)
+        }
+        RegExpr::Group(ref expr, group) => {
+            // the boundary nodes sit inside plain start and end nodes, so the
+            // epsilon edges a surrounding `*` adds between those never pass them
+            let inner = build(expr, alloc, counting);
+            let start = Node::new(alloc);
+            let open = Node::new(alloc);
+            let close = Node::new(alloc);
+            let end = Node::new(alloc);
+
+            let mut graph = Graph::new(start);
+            graph.edges = inner.edges;
+            graph.groups = inner.groups;
+            graph.add_edge(Condition::Epsilon, start, open);
+            graph.add_edge(Condition::Epsilon, open, inner.start);
+            for acceptor in inner.acceptors {
+                graph.add_edge(Condition::Epsilon, acceptor, close);
+            }
+            graph.add_edge(Condition::Epsilon, close, end);
+            graph.groups.insert(open, GroupBoundary::Open(group));
+            graph.groups.insert(close, GroupBoundary::Close(group));
+            graph.acceptors.insert(end);
+            graph
+        }
+    }
+}
+
+// lhs followed by rhs, leaving out empty sequences
+fn concatenation(lhs: RegExpr, rhs: RegExpr) -> RegExpr {
+    let mut v = vec![];
+    for expr in [lhs, rhs] {
+        match expr {
+            RegExpr::Sequence(inner) => v.extend(inner),
+            expr => v.push(expr),
+        }
+    }
+    if v.len() == 1 {
+        v.pop().unwrap()
+    } else {
+        RegExpr::Sequence(v)
     }
 }
 
+// adds expr as another way from one state to the other
+fn add_label(labels: &mut BTreeMap<(usize, usize), RegExpr>, from: usize, to: usize, expr: RegExpr) {
+    let expr = match labels.remove(&(from, to)) {
+        Some(ref old) if *old == expr => expr,
+        Some(old) => RegExpr::Branch(Box::new(old), Box::new(expr)),
+        None => expr,
+    };
+    labels.insert((from, to), expr);
+}
+
+// the classic conversion of an automaton into an expression. labels holds
+// the expression for the way between each pair of states, numbered from 0 to
+// count. with a fresh initial and final state around them, every state is
+// removed in turn, replacing each path through it by a single label, until
+// only the label from the initial to the final state is left. the result
+// matches the same strings but is far from the shortest such expression.
+fn eliminate_states(count: usize,
+                    start: usize,
+                    acceptors: &BTreeSet<usize>,
+                    mut labels: BTreeMap<(usize, usize), RegExpr>)
+                    -> RegExpr {
+    let (initial, last) = (count, count + 1);
+    add_label(&mut labels, initial, start, RegExpr::Sequence(vec![]));
+    for &acceptor in acceptors {
+        add_label(&mut labels, acceptor, last, RegExpr::Sequence(vec![]));
+    }
+
+    for state in 0..count {
+        // an epsilon loop adds nothing
+        let looped = match labels.remove(&(state, state)) {
+            Some(RegExpr::Sequence(ref v)) if v.is_empty() => None,
+            Some(expr) => Some(RegExpr::Repeation(Box::new(expr))),
+            None => None,
+        };
+        let incoming: Vec<(usize, usize)> = labels.keys().filter(|&&(_, to)| to == state).cloned().collect();
+        let incoming: Vec<(usize, RegExpr)> = incoming.into_iter()
+            .map(|key| (key.0, labels.remove(&key).unwrap()))
+            .collect();
+        let outgoing: Vec<(usize, usize)> = labels.keys().filter(|&&(from, _)| from == state).cloned().collect();
+        let outgoing: Vec<(usize, RegExpr)> = outgoing.into_iter()
+            .map(|key| (key.1, labels.remove(&key).unwrap()))
+            .collect();
+        for &(from, ref into) in &incoming {
+            for &(to, ref out) in &outgoing {
+                let through = match looped {
+                    Some(ref looped) => concatenation(into.clone(), looped.clone()),
+                    None => into.clone(),
+                };
+                add_label(&mut labels, from, to, concatenation(through, out.clone()));
+            }
+        }
+    }
+    // an empty class, matching nothing, when no acceptor can be reached
+    labels.remove(&(initial, last)).unwrap_or(RegExpr::Range(vec![]))
+}
+
 fn reachable_through_epsilon(graph: &Graph, nodes: &BTreeSet<Node>) -> BTreeSet<Node> {
+    reachable_through(graph, nodes, &[Condition::Epsilon])
+}
+
+// nodes reachable from nodes along edges with one of the zero-width conditions
+fn reachable_through(graph: &Graph, nodes: &BTreeSet<Node>, conditions: &[Condition]) -> BTreeSet<Node> {
+    // only those edges are followed: a node first reached through a char
+    // edge may still be reachable along another path
     let mut ret: BTreeSet<Node> = nodes.clone();
-    for node in nodes {
-        graph.traverse(&mut |edge| {
-                           if edge.condition.is_none() && ret.contains(&edge.from) {
-                               ret.insert(edge.to);
-                           }
-                       },
-                       &node,
-                       &mut HashSet::new());
+    let mut pending: Vec<Node> = nodes.iter().cloned().collect();
+    while let Some(node) = pending.pop() {
+        for edge in graph.edges.iter().filter(|edge| edge.from == node) {
+            if conditions.contains(&edge.condition) && ret.insert(edge.to) {
+                pending.push(edge.to);
+            }
+        }
     }
     ret
 }
 
+// what the subset construction in build_dfa went through
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Default)]
+pub struct DFAMetrics {
+    // number of DFA states expanded, one per loop iteration
+    pub iterations: usize,
+    // largest number of discovered but not yet expanded states
+    pub peak_pending: usize,
+    pub transitions: usize,
+}
+
 pub fn build_dfa(graph: &Graph) -> DFA {
+    build_dfa_with_metrics(graph).0
+}
+
+pub fn build_dfa_with_metrics(graph: &Graph) -> (DFA, DFAMetrics) {
+    let mut metrics = DFAMetrics::default();
+    let marker = graph.unused_node();
+    // what can be crossed once the input ends, and once it ends right at its start
+    let end_conditions = [Condition::Epsilon, Condition::EndAnchor];
+    let empty_conditions = [Condition::Epsilon, Condition::EndAnchor, Condition::StartAnchor];
     let mut target = DFANode::new(reachable_through_epsilon(graph,
                                                             &[graph.start]
                                                                  .iter()
@@ -349,26 +1861,106 @@ pub fn build_dfa(graph: &Graph) -> DFA {
     dfa_nodes.insert(target.clone());
     let mut processed_nodes: BTreeSet<DFANode> = BTreeSet::new();
     loop {
-        let mut successors: HashMap<char, BTreeSet<Node>> = HashMap::new();
-        for &Edge { from: _, to, condition } in graph.edges.iter().filter(|edge| {
-            target.nodes.contains(&edge.from)
-        }) {
-            match condition {
-                Some(c) => {
-                    successors.entry(c).or_insert(BTreeSet::new()).insert(to);
+        metrics.iterations += 1;
+        metrics.peak_pending = metrics.peak_pending.max(dfa_nodes.len());
+        let outgoing: Vec<&Edge> = graph.edges
+                                        .iter()
+                                        .filter(|edge| target.nodes.contains(&edge.from))
+                                        .collect();
+        // each char mentioned by an outgoing edge gets its own transition,
+        // the stretches of chars between the ends of classes get one each,
+        // and all the other chars can only be consumed by wildcards
+        let mut chars: BTreeSet<char> = BTreeSet::new();
+        let mut intervals: Vec<(char, char)> = vec![];
+        for edge in &outgoing {
+            match edge.condition {
+                Condition::Char(c) => {
+                    chars.insert(c);
+                }
+                Condition::Except(ref set) => chars.extend(set),
+                Condition::Ranges(ref ranges) => intervals.extend(ranges),
+                _ => {}
+            }
+        }
+        // a single pass over the Char edges rather than one per char, and the
+        // classes are only looked up once per stretch, which keeps large
+        // classes like those of `\p{..}` affordable
+        let wildcards: Vec<&Edge> = outgoing.iter()
+                                            .filter(|edge| {
+                                                matches!(edge.condition, Condition::Any | Condition::Except(_))
+                                            })
+                                            .cloned()
+                                            .collect();
+        let classes: Vec<&Edge> = outgoing.iter()
+                                          .filter(|edge| matches!(edge.condition, Condition::Ranges(_)))
+                                          .cloned()
+                                          .collect();
+        let consuming = |c: char| -> BTreeSet<Node> {
+            wildcards.iter().chain(&classes).filter(|edge| edge.condition.matches(c)).map(|edge| edge.to).collect()
+        };
+        let mut successors: HashMap<DFACondition, BTreeSet<Node>> = chars.iter()
+            .map(|&c| (DFACondition::Char(c), consuming(c)))
+            .collect();
+        for edge in &outgoing {
+            if let Condition::Char(c) = edge.condition {
+                successors.get_mut(&DFACondition::Char(c)).unwrap().insert(edge.to);
+            }
+        }
+        // neighbouring stretches going to the same nodes share a transition
+        let mut stretches: Vec<((char, char), BTreeSet<Node>)> = vec![];
+        for (first, last) in ranges::split(&intervals, &chars) {
+            let nodes = consuming(first);
+            match stretches.last_mut() {
+                Some(&mut ((_, ref mut end), ref to)) if ranges::next(*end) == Some(first) && *to == nodes => {
+                    *end = last;
                 }
-                None => {}
+                _ => stretches.push(((first, last), nodes)),
+            }
+        }
+        for ((first, last), nodes) in stretches {
+            let condition = if first == last {
+                DFACondition::Char(first)
+            } else {
+                DFACondition::Range(first, last)
+            };
+            successors.insert(condition, nodes);
+        }
+        let other: BTreeSet<Node> = wildcards.iter().map(|edge| edge.to).collect();
+        if other.is_empty() {
+            successors.retain(|_, nodes| !nodes.is_empty());
+        } else {
+            // chars excluded by every wildcard keep a transition into the
+            // empty dead state, otherwise Other would take them
+            successors.insert(DFACondition::Other, other);
+        }
+        // `^` can only be crossed before any input is consumed, `$` once it has all been.
+        // the state past `^` holds the marker, so it is never mistaken for one reached
+        // by consuming input, and for empty input `^` and `$` may be crossed in any order.
+        if target == ret.start {
+            let mut start = reachable_through(graph, &target.nodes, &[Condition::Epsilon, Condition::StartAnchor]);
+            let empty = reachable_through(graph, &start, &empty_conditions);
+            if start != target.nodes || empty != reachable_through(graph, &target.nodes, &end_conditions) {
+                start.insert(marker);
+                successors.insert(DFACondition::StartAnchor, start);
             }
         }
-        {
-            successors = successors.iter()
-                                   .map(|(c, nodes)| {
-                                       (*c, reachable_through_epsilon(graph, &nodes))
-                                   })
-                                   .collect();
+        let end = if target.nodes.contains(&marker) {
+            reachable_through(graph, &target.nodes, &empty_conditions)
+        } else {
+            reachable_through(graph, &target.nodes, &end_conditions)
+        };
+        if end != target.nodes {
+            successors.insert(DFACondition::EndAnchor, end);
         }
-        for (c, successor) in successors {
-            let node = DFANode::new(successor, graph);
+        // the chars of a class mostly lead to the same nodes, so each
+        // distinct set is only closed and turned into a state once
+        let mut states: HashMap<BTreeSet<Node>, DFANode> = HashMap::new();
+        for (c, nodes) in successors {
+            let node = states.entry(nodes)
+                             .or_insert_with_key(|nodes| {
+                                 DFANode::new(reachable_through_epsilon(graph, nodes), graph)
+                             })
+                             .clone();
             if !processed_nodes.contains(&node) && !dfa_nodes.contains(&node) {
                 dfa_nodes.insert(node.clone());
             }
@@ -389,5 +1981,364 @@ pub fn build_dfa(graph: &Graph) -> DFA {
             }
         }
     }
-    ret
+    metrics.transitions = ret.edges.len();
+    (ret, metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::Engine;
+    use parser::parse;
+
+    fn nfa(pattern: &str) -> Graph {
+        build_nfa(&parse(&mut pattern.chars()).unwrap(), &mut NodeAllocator::new())
+    }
+
+    fn dfa(pattern: &str) -> DFA {
+        build_dfa(&nfa(pattern))
+    }
+
+    fn chars(s: &str) -> BTreeSet<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn sccs() {
+        assert!(nfa("a*").sccs().iter().any(|component| component.len() > 1));
+        assert!(nfa("ab").sccs().iter().all(|component| component.len() == 1));
+        let graph = nfa("(ab)*c");
+        let nodes: usize = graph.sccs().iter().map(|component| component.len()).sum();
+        assert_eq!(nodes, graph.node_count());
+        let mut sizes: Vec<usize> = nfa("x(ab)*c").sccs().iter().map(|component| component.len()).collect();
+        sizes.sort();
+        assert_eq!(sizes.pop(), Some(10));
+        assert!(sizes.iter().all(|&size| size == 1));
+    }
+
+    #[test]
+    fn sccs_of_long_chains() {
+        let chain = RegExpr::Sequence(vec![RegExpr::Character('a'); 20000]);
+        let graph = build_nfa(&RegExpr::Repeation(Box::new(chain)), &mut NodeAllocator::new());
+        let sccs = graph.sccs();
+        assert!(sccs.iter().any(|component| component.len() > 40000));
+        assert_eq!(sccs.iter().map(|component| component.len()).sum::<usize>(), graph.node_count());
+    }
+
+    #[test]
+    fn node_count() {
+        // a start and an end around the two nodes of every char
+        assert_eq!(nfa("abc").node_count(), 8);
+        assert_eq!(nfa(&"a".repeat(1000)).node_count(), 2002);
+        // the node ids allocated while building, including those of merged sub-NFAs
+        let mut alloc = NodeAllocator::new();
+        build_nfa(&parse(&mut "abc".chars()).unwrap(), &mut alloc);
+        assert_eq!(alloc.nodes.len(), 8);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = dfa("(ab)*|\"c|^x.$").to_json();
+        let loaded = DFA::from_json(&json).unwrap();
+        assert_eq!(loaded.to_json(), json);
+        let engine = Engine::new(loaded);
+        assert!(engine.match_string("abab"));
+        assert!(engine.match_string("\"c"));
+        assert!(engine.match_string("xy"));
+        assert!(!engine.match_string("a"));
+        assert!(DFA::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn nfa_table_round_trip() {
+        let table = nfa("(ab)*|c").to_nfa_table().unwrap();
+        let graph = Graph::from_nfa_table(&table, &mut NodeAllocator::new());
+        assert_eq!(graph.to_nfa_table(), Some(table));
+        assert_eq!(graph.captures("abab"), Some(vec![Some((0, 4)), Some((2, 4))]));
+        assert_eq!(nfa("a.").to_nfa_table(), None);
+        assert_eq!(nfa("^a").to_nfa_table(), None);
+    }
+
+    #[test]
+    fn equality_up_to_state_numbering() {
+        assert_eq!(dfa("(a|b)"), dfa("(b|a)"));
+        assert_eq!(dfa("a(b|c)*"), dfa("a(c|b)*"));
+        assert!(dfa("(a|b)") != dfa("(a|c)"));
+        assert!(dfa("a*") != dfa("a+"));
+        assert!(nfa("(a|b)") != nfa("(b|a)"));
+    }
+
+    #[test]
+    fn nfa_table_simulation() {
+        // whether table accepts s, following its edges directly
+        fn accepts(table: &NFATable, s: &str) -> bool {
+            let close = |mut nodes: Vec<usize>| {
+                let mut closure = BTreeSet::new();
+                while let Some(node) = nodes.pop() {
+                    if closure.insert(node) {
+                        nodes.extend(table.edges[node].iter().filter(|edge| edge.0.is_none()).map(|edge| edge.1));
+                    }
+                }
+                closure
+            };
+            let mut current = close(vec![table.start]);
+            for c in s.chars() {
+                current = close(current.iter()
+                    .flat_map(|&node| table.edges[node].iter().filter(|edge| edge.0 == Some(c)).map(|edge| edge.1))
+                    .collect());
+            }
+            current.iter().any(|node| table.acceptors.contains(node))
+        }
+
+        for pattern in &["(ab)*|c", "a(b|c)*d", "(a|ab)(c|bcd)", "x?y{2,3}", ""] {
+            let graph = nfa(pattern);
+            let table = graph.to_nfa_table().unwrap();
+            let engine = Engine::new(build_dfa(&graph));
+            for s in &["", "ab", "abab", "c", "ad", "abcbd", "abcd", "yy", "xyyy", "xyyyy"] {
+                assert_eq!(accepts(&table, s), engine.match_string(s), "{:?} on {:?}", pattern, s);
+            }
+        }
+    }
+
+    #[test]
+    fn reduce_epsilon_edges() {
+        let epsilons = |graph: &Graph| graph.edges.iter().filter(|edge| edge.condition == Condition::Epsilon).count();
+        // these have epsilon edges to spare, the others may not
+        let redundant = ["(a|b)*", "(ab)*c|d+", "x(|a)*y", "(a*)*"];
+        for pattern in redundant.iter().chain(&["a{2,4}", "^a|b$", ""]) {
+            let mut graph = nfa(pattern);
+            let (before, dfa) = (epsilons(&graph), build_dfa(&graph));
+            graph.reduce_epsilon_edges();
+            if redundant.contains(pattern) {
+                assert!(epsilons(&graph) < before, "{:?}", pattern);
+            } else {
+                assert!(epsilons(&graph) <= before, "{:?}", pattern);
+            }
+            assert_eq!(build_dfa(&graph), dfa, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn union() {
+        let engine = Engine::new(DFA::union(&[&dfa("a.c"), &dfa("(bc)*"), &dfa("^x$")]));
+        for s in &["abc", "azc", "", "bcbc", "x"] {
+            assert!(engine.match_string(s), "{:?}", s);
+        }
+        for s in &["ab", "b", "xx"] {
+            assert!(!engine.match_string(s), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn from_literals() {
+        let dfa = DFA::from_literals(&["foo", "bar", "baz", "fo"]);
+        // the trie shares the prefixes `fo` and `ba`
+        assert_eq!(dfa.states().len(), 8);
+        let engine = Engine::new(dfa);
+        assert!(engine.match_string("foo"));
+        assert!(engine.match_string("fo"));
+        assert!(!engine.match_string("foobar"));
+        assert!(!engine.match_string(""));
+    }
+
+    #[test]
+    fn validate_deterministic() {
+        assert!(dfa("(ab)*|a.").validate_deterministic().is_ok());
+        let json = "{\"states\":[0,1,2],\"start\":0,\"accepting\":[1],\"transitions\":[{\"from\":0,\"to\":1,\
+                    \"condition\":\"a\"},{\"from\":0,\"to\":2,\"condition\":\"a\"}]}";
+        assert!(DFA::from_json(json).unwrap().validate_deterministic().is_err());
+    }
+
+    #[test]
+    fn universal_states() {
+        let dfa = dfa("a(a|b)*");
+        assert!(!dfa.universal_states().is_empty());
+        assert!(!dfa.universal_states().contains(&&dfa.start));
+        assert!(self::dfa("a(ab)*").universal_states().is_empty());
+        let wildcard = self::dfa(".*");
+        assert!(wildcard.universal_states().contains(&&wildcard.start));
+        let prefixed = self::dfa("a.*");
+        assert_eq!(prefixed.universal_states().len(), 2);
+        assert!(!prefixed.universal_states().contains(&&prefixed.start));
+    }
+
+    #[test]
+    fn deep_graph() {
+        // the NFA of 100k concatenated chars, built by hand since parsing a
+        // pattern that long takes a deeper stack than the test threads have
+        let mut alloc = NodeAllocator::new();
+        let start = Node::new(&mut alloc);
+        let mut graph = Graph::new(start);
+        let mut last = start;
+        for _ in 0..100_000 {
+            let next = Node::new(&mut alloc);
+            graph.add_edge(Condition::Char('a'), last, next);
+            last = next;
+        }
+        graph.acceptors.insert(last);
+        let mut out = vec![];
+        graph.dotty_print(&mut out);
+        let dot = String::from_utf8(out).unwrap();
+        assert_eq!(dot.matches(" -> ").count(), graph.edges.len());
+    }
+
+    #[test]
+    fn dead_states() {
+        assert_eq!(dfa("a[^b]").dead_states().len(), 1);
+    }
+
+    fn dotty(dfa: &DFA, format: LabelFormat) -> String {
+        let mut out = vec![];
+        dfa.dotty_print_with(&mut out, format);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn dotty_print_clusters_dead_states() {
+        let trapping = dfa("a[^b]");
+        let dead = trapping.dead_states();
+        let id = trapping.state_ids()[dead[0]];
+        let cluster = format!("\tsubgraph cluster_dead {{\n\t\tlabel = \"dead\";\n\t\tstyle = \"dashed\";\n\t\t{};\n\t}}\n", id);
+        assert!(dotty(&trapping, LabelFormat::Id).contains(&cluster));
+        assert!(!dotty(&dfa("ab"), LabelFormat::Id).contains("cluster_dead"));
+    }
+
+    #[test]
+    fn dotty_print_with_ids() {
+        assert_eq!(dotty(&dfa("a|b"), LabelFormat::Id),
+                   "digraph g{\n\
+                    \t0 -> 1 [ label = \"a\" ];\n\
+                    \t1 [ style = \"bold\" ];\n\
+                    \t0 -> 2 [ label = \"b\" ];\n\
+                    \t2 [ style = \"bold\" ];\n\
+                    }\n");
+    }
+
+    #[test]
+    fn is_infinite() {
+        assert!(dfa("ab*").is_infinite());
+        assert!(!dfa("ab|c").is_infinite());
+        assert!(dfa("a[^a]*").is_infinite());
+    }
+
+    #[test]
+    fn counterexample() {
+        assert_eq!(dfa("a*").counterexample(&chars("ab")), Some("b".to_owned()));
+        assert_eq!(dfa("(ab)*").counterexample(&chars("ab")), Some("a".to_owned()));
+        assert_eq!(dfa("(a|b)*").counterexample(&chars("ab")), None);
+    }
+
+    #[test]
+    fn count_accepted() {
+        assert_eq!(dfa("a|bc").count_accepted(1), 1);
+        assert_eq!(dfa("a|bc").count_accepted(2), 2);
+        assert_eq!(dfa("(a|b)*").count_accepted(3), 15);
+    }
+
+    #[test]
+    fn difference_witness() {
+        assert_eq!(dfa("a*").difference_witness(&dfa("a+")), Some("".to_owned()));
+        assert_eq!(dfa("a.c").difference_witness(&dfa("abc")), Some("aac".to_owned()));
+        assert_eq!(dfa("(a|b)*").difference_witness(&dfa("(a*b*)*")), None);
+    }
+
+    #[test]
+    fn intersection_witness() {
+        assert_eq!(dfa("ab").intersection_witness(&dfa("a.")), Some("ab".to_owned()));
+        assert_eq!(dfa("a*").intersection_witness(&dfa("b*")), Some("".to_owned()));
+        assert_eq!(dfa("a+").intersection_witness(&dfa("b+")), None);
+    }
+
+    #[test]
+    fn to_regex() {
+        for pattern in &["(ab)*c", "a[^b]|b", "^a*$"] {
+            let expr = dfa(pattern).to_regex();
+            let rebuilt = build_dfa(&build_nfa(&expr, &mut NodeAllocator::new()));
+            assert_eq!(dfa(pattern).difference_witness(&rebuilt), None, "{:?}", pattern);
+            assert_eq!(rebuilt.difference_witness(&dfa(pattern)), None, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn range_edges() {
+        let class = |ranges: &[(char, char)]| Box::new(RegExpr::Ranges(ranges.to_vec()));
+        // cut where either class starts or ends, and around the k
+        let either = RegExpr::Branch(class(&[('h', 'z')]), Box::new(RegExpr::Character('k')));
+        let dfa = build_dfa(&build_nfa(&RegExpr::Branch(class(&[('a', 'm')]), Box::new(either)),
+                                       &mut NodeAllocator::new()));
+        let conditions: Vec<String> = dfa.edges.iter().map(|edge| edge.condition.to_string()).collect();
+        assert_eq!(conditions, ["k", "a-g", "h-j", "l-m", "n-z"]);
+        assert_eq!(dfa.alphabet(), chars("\0ahkln{"));
+        assert_eq!(dfa.count_accepted(1), 26);
+        assert_eq!(dfa.difference_witness(&self::dfa("[a-z]")), None);
+        assert_eq!(self::dfa("[a-z]").difference_witness(&dfa), None);
+        assert_eq!(DFA::from_json(&dfa.to_json()).unwrap().to_json(), dfa.to_json());
+        let rebuilt = build_dfa(&build_nfa(&dfa.to_regex(), &mut NodeAllocator::new()));
+        assert_eq!(rebuilt.difference_witness(&dfa), None);
+        assert_eq!(dfa.difference_witness(&rebuilt), None);
+        let engine = Engine::new(DFA::union(&[&dfa, &self::dfa("[^e]")]));
+        for s in &["a", "e", "k", "z", "A"] {
+            assert!(engine.match_string(s), "{:?}", s);
+        }
+        assert!(!engine.match_string("ee"));
+
+        // neighbouring stretches going to the same nodes are merged
+        let mut alloc = NodeAllocator::new();
+        let (start, end) = (Node::new(&mut alloc), Node::new(&mut alloc));
+        let mut graph = Graph::new(start);
+        for &range in &[('a', 'c'), ('d', 'f'), ('e', 'e')] {
+            graph.add_edge(Condition::Ranges(vec![range]), start, end);
+        }
+        graph.acceptors.insert(end);
+        let conditions: Vec<DFACondition> = build_dfa(&graph).edges.iter().map(|edge| edge.condition).collect();
+        assert_eq!(conditions, [DFACondition::Range('a', 'f')]);
+
+        let json = "{\"states\":[0,1,2],\"start\":0,\"accepting\":[1,2],\"transitions\":[{\"from\":0,\"to\":1,\
+                    \"condition\":{\"range\":[\"a\",\"c\"]}},{\"from\":0,\"to\":2,\"condition\":{\"range\":[\"b\",\"d\"]}}]}";
+        assert!(DFA::from_json(json).unwrap().validate_deterministic().is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        let (dfa, metrics) = build_dfa_with_metrics(&nfa("(aa|b)*"));
+        assert_eq!(metrics.iterations, dfa.states().len());
+        assert_eq!(metrics.transitions, dfa.edges.len());
+    }
+
+    #[test]
+    fn counters() {
+        let counted = |pattern: &str, threshold: usize| {
+            build_nfa_with_counters(&parse(&mut pattern.chars()).unwrap(), &mut NodeAllocator::new(), threshold)
+        };
+        assert_eq!(counted("a{1000}", 1000).node_count(), 8);
+        assert_eq!(counted("a{1000,}", 1000).node_count(), 7);
+        assert_eq!(counted("a{999}", 1000).graph, nfa("a{999}"));
+        assert!(counted("a{999}", 1000).counters.is_empty());
+        assert_eq!(counted("(a{2}){3}", 2).counters.len(), 6);
+        let graph = counted("a{2}", 2);
+        let once = graph.step(&graph.start_states(), 'a');
+        assert!(!graph.accepts_at_end(&once));
+        assert!(graph.accepts_at_end(&graph.step(&once, 'a')));
+        assert!(!graph.accepts_empty());
+        assert!(counted("a{0,2}", 2).accepts_empty());
+    }
+
+    #[test]
+    fn captures() {
+        let graph = nfa("(a*)(a)");
+        assert_eq!(graph.group_count(), 2);
+        assert_eq!(graph.captures("aaa"), Some(vec![Some((0, 3)), Some((0, 2)), Some((2, 3))]));
+        assert_eq!(graph.captures("b"), None);
+        assert_eq!(graph.captures_at("xaaay", (1, 4)), Some(vec![Some((1, 4)), Some((1, 3)), Some((3, 4))]));
+        assert_eq!(graph.captures_at("xaaay", (1, 5)), None);
+        let graph = nfa("(^a)?(a*)");
+        assert_eq!(graph.captures_at("aa", (1, 2)), Some(vec![Some((1, 2)), None, Some((1, 2))]));
+        assert_eq!(graph.captures_at("b", (0, 0)), Some(vec![Some((0, 0)), None, Some((0, 0))]));
+        let graph = nfa("(a)|b");
+        assert_eq!(graph.captures_spans("ab a", vec![(0, 1), (1, 2), (1, 3), (3, 4)]),
+                   vec![Some(vec![Some((0, 1)), Some((0, 1))]),
+                        Some(vec![Some((1, 2)), None]),
+                        None,
+                        Some(vec![Some((3, 4)), Some((3, 4))])]);
+    }
 }
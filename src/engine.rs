@@ -1,42 +1,880 @@
+use automaton::CountedGraph;
 use automaton::DFA;
-use automaton::DFANode;
-use std::collections::BTreeMap;
+use automaton::DFACondition;
+use automaton::Graph;
+use automaton::GroupSpans;
+use automaton::NodeAllocator;
+use automaton::build_nfa_with_counters;
+use parser::ParseError;
+use parser::parse;
+use positions::PositionMap;
 use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::str;
+use std::str::CharIndices;
 
+// states with at most this many transitions keep them in a sorted Vec
+const SPARSE_LIMIT: usize = 8;
+
+// outgoing transitions of a single state, by target state index
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum Transitions{
+    Sparse(Vec<(char,usize)>),
+    Dense(HashMap<char,usize>)
+}
+
+impl Transitions{
+    fn new(map: HashMap<char,usize>) -> Transitions{
+        if map.len() <= SPARSE_LIMIT{
+            let mut v: Vec<(char,usize)> = map.into_iter().collect();
+            v.sort_by_key(|&(c,_)| c);
+            Transitions::Sparse(v)
+        } else {
+            Transitions::Dense(map)
+        }
+    }
+
+    fn get(&self,c: char) -> Option<usize>{
+        match *self{
+            Transitions::Sparse(ref v) => v.binary_search_by_key(&c,|&(c,_)| c).ok().map(|i| v[i].1),
+            Transitions::Dense(ref map) => map.get(&c).cloned()
+        }
+    }
+}
+
+// text the engine can match over without it being one contiguous str,
+// like the chunks of a rope. positions are byte offsets into the whole text.
+pub trait TextSource{
+    type Chars: Iterator<Item = (usize,char)>;
+
+    fn char_indices(self) -> Self::Chars;
+}
+
+impl<'a> TextSource for &'a str{
+    type Chars = CharIndices<'a>;
+
+    fn char_indices(self) -> CharIndices<'a>{
+        str::char_indices(self)
+    }
+}
+
+// states are numbered once in new, so matching only moves indices around.
+// with the serde feature a compiled engine can be saved and loaded again
+// without parsing or building automata.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Engine{
-    start: DFANode,
-    edges: BTreeMap<DFANode,HashMap<char,DFANode>>
+    start: usize,
+    // where matching starts at the beginning of the input, past any `^`
+    input_start: usize,
+    // the following are indexed by state
+    edges: Vec<Transitions>,
+    // the first and last char of each range transition with its target,
+    // sorted, for chars without a transition in edges
+    ranges: Vec<Vec<(char,char,usize)>>,
+    // where each state goes on chars without a transition in edges or ranges
+    others: Vec<Option<usize>>,
+    acceptors: Vec<bool>,
+    // also true for states accepting through `$` when the input ends there
+    end_acceptors: Vec<bool>,
+    // states from which no input can lead to acceptance any more
+    dead: Vec<bool>
+}
+
+// outcome of Engine::run
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct MatchResult{
+    pub accepted: bool,
+    // chars read before the walk stopped, including the one leading into a dead state
+    pub consumed: usize,
+    pub ended_in_dead_state: bool
 }
 
 impl Engine{
+    // panics if dfa has two transitions out of a state on the same char,
+    // see try_new
     pub fn new(dfa: DFA) -> Engine{
-        let mut edges: BTreeMap<DFANode,HashMap<char,DFANode>> = BTreeMap::new();
-        for edge in dfa.edges.into_iter(){
-            assert!(edges.entry(edge.from).or_insert(HashMap::new()).insert(edge.condition,edge.to).is_none());
-        }
-
-        Engine{ start: dfa.start, edges: edges }
-    }
-
-    pub fn match_string(&self,s: &str) -> bool{
-        let mut current = self.start.clone();
-        let mut iter = s.chars();
-        loop{
-            match iter.next(){
-                None => { return current.is_acceptor; },
-                Some(c) => {
-                   match self.edges.get(&current){
-                       None => { return false; },
-                       Some(edge) => {
-                           match edge.get(&c){
-                               None => { return false; },
-                               Some(to) => { current = to.clone(); }
-                           }
-                       }
-                   }
+        Engine::try_new(dfa).unwrap_or_else(|error| panic!("{}",error))
+    }
+
+    // like new, but a DFA that is not deterministic, like a hand-built one,
+    // is an error, see DFA::validate_deterministic
+    pub fn try_new(dfa: DFA) -> Result<Engine,String>{
+        dfa.validate_deterministic()?;
+        let ids = dfa.state_ids();
+        let mut edges: Vec<HashMap<char,usize>> = vec![HashMap::new(); ids.len()];
+        let mut ranges: Vec<Vec<(char,char,usize)>> = vec![vec![]; ids.len()];
+        let mut others: Vec<Option<usize>> = vec![None; ids.len()];
+        let mut acceptors: Vec<bool> = vec![false; ids.len()];
+        for (state,&id) in &ids{
+            acceptors[id] = state.is_acceptor;
+        }
+        let mut end_acceptors = acceptors.clone();
+        let start = ids[&dfa.start];
+        let mut input_start = start;
+        // state_ids leaves out states unreachable from start, and their edges with them
+        for edge in dfa.edges.iter().filter(|edge| ids.contains_key(&edge.from)){
+            let (from,to) = (ids[&edge.from],ids[&edge.to]);
+            match edge.condition{
+                DFACondition::Char(c) => {
+                    edges[from].insert(c,to);
+                },
+                DFACondition::Range(first,last) => {
+                    ranges[from].push((first,last,to));
+                },
+                DFACondition::Other => {
+                    others[from] = Some(to);
+                },
+                DFACondition::StartAnchor => {
+                    if from == start{
+                        input_start = to;
+                    }
+                },
+                DFACondition::EndAnchor => {
+                    end_acceptors[from] |= acceptors[to];
+                }
+            }
+        }
+        // dfa.edges is sorted, so ranges already are
+        let dead = dead_states(&edges,&ranges,&others,&end_acceptors);
+        let edges = edges.into_iter().map(Transitions::new).collect();
+
+        Ok(Engine{ start, input_start, edges, ranges, others, acceptors, end_acceptors, dead })
+    }
+
+    fn step(&self,current: usize,c: char) -> Option<usize>{
+        let ranges = &self.ranges[current];
+        // the last range starting at or before c is the only one that can hold it
+        let range = || match ranges.partition_point(|&(first,_,_)| first <= c){
+            0 => None,
+            i => Some(ranges[i - 1]).filter(|&(_,last,_)| c <= last).map(|(_,_,to)| to)
+        };
+        self.edges[current].get(c).or_else(range).or(self.others[current])
+    }
+
+    pub fn match_string<S: AsRef<str>>(&self,s: S) -> bool{
+        self.match_source(s.as_ref())
+    }
+
+    // like match_string, over any source of text
+    pub fn match_source<T: TextSource>(&self,text: T) -> bool{
+        let mut current = self.input_start;
+        for (_,c) in text.char_indices(){
+            match self.step(current,c){
+                None => { return false; },
+                Some(to) => { current = to; }
+            }
+        }
+        self.end_acceptors[current]
+    }
+
+    // like match_string, but also reports how far the input was read.
+    // the walk stops early once it reaches a dead state, since nothing read
+    // after that can make the input match.
+    pub fn run(&self,s: &str) -> MatchResult{
+        let mut current = Some(self.input_start);
+        let mut consumed = 0;
+        for c in s.chars(){
+            match current{
+                Some(state) if !self.dead[state] => {
+                    current = self.step(state,c);
+                    consumed += 1;
+                },
+                _ => { break; }
+            }
+        }
+        match current{
+            Some(state) if !self.dead[state] => MatchResult{
+                accepted: self.end_acceptors[state],
+                consumed,
+                ended_in_dead_state: false
+            },
+            _ => MatchResult{ accepted: false, consumed, ended_in_dead_state: true }
+        }
+    }
+
+    // match_string over raw bytes. the pattern is over chars, so bytes that
+    // are not valid UTF-8 never match, not even `.` or `[^...]`.
+    pub fn match_bytes(&self,bytes: &[u8]) -> bool{
+        str::from_utf8(bytes).is_ok_and(|s| self.match_string(s))
+    }
+
+    // the leftmost match, and the longest among those starting there.
+    // offsets are in bytes.
+    pub fn find<S: AsRef<str>>(&self,s: S) -> Option<(usize,usize)>{
+        let s = s.as_ref();
+        let starts = s.char_indices().map(|(i,_)| i).chain(Some(s.len()));
+        for start in starts{
+            if let Some(end) = self.longest_match_at(s,start){
+                return Some((start,end));
+            }
+        }
+        None
+    }
+
+    // like find, but the offsets count chars instead of bytes
+    pub fn find_char_indices<S: AsRef<str>>(&self,s: S) -> Option<(usize,usize)>{
+        let s = s.as_ref();
+        self.find(s).map(|span| PositionMap::new(s).steps(span))
+    }
+
+    // the rightmost match is the one ending furthest to the right;
+    // among those ending at the same offset the longest one wins.
+    // offsets are in bytes.
+    //
+    // a single pass over s follows a match attempt from every offset at once.
+    // attempts in the same state go on alike, so only the one that started
+    // first is kept, and the pass takes time linear in the length of s.
+    pub fn rfind<S: AsRef<str>>(&self,s: S) -> Option<(usize,usize)>{
+        let s = s.as_ref();
+        let mut found: Option<(usize,usize)> = None;
+        // the states of the live attempts, with where each of them started
+        let mut active: Vec<usize> = vec![];
+        let mut started: Vec<Option<usize>> = vec![None; self.edges.len()];
+        let offsets = s.char_indices().map(|(i,c)| (i,Some(c))).chain(Some((s.len(),None)));
+        for (offset,c) in offsets{
+            // every attempt running so far started earlier than this one
+            let state = if offset == 0 { self.input_start } else { self.start };
+            if started[state].is_none(){
+                started[state] = Some(offset);
+                active.push(state);
+            }
+            let accepted = active.iter()
+                .filter(|&&state| if c.is_none() { self.end_acceptors[state] } else { self.acceptors[state] })
+                .filter_map(|&state| started[state])
+                .min();
+            if let Some(start) = accepted{
+                found = Some((start,offset));
+            }
+
+            let c = match c{
+                Some(c) => c,
+                None => break
+            };
+            let mut next: Vec<usize> = vec![];
+            let mut next_started: Vec<(usize,usize)> = vec![];
+            for state in active.drain(..){
+                let start = started[state].take().unwrap();
+                if let Some(to) = self.step(state,c).filter(|&to| !self.dead[to]){
+                    next_started.push((to,start));
+                }
+            }
+            for (to,start) in next_started{
+                match started[to]{
+                    Some(earlier) if earlier <= start => {},
+                    None => {
+                        started[to] = Some(start);
+                        next.push(to);
+                    },
+                    Some(_) => { started[to] = Some(start); }
+                }
+            }
+            active = next;
+        }
+        found
+    }
+
+    // calls f with the byte offsets of every match of find_iter, in order,
+    // without allocating. f returns whether to go on to the next match.
+    pub fn for_each_match<S: AsRef<str>,F: FnMut(usize,usize) -> bool>(&self,s: S,mut f: F){
+        let s = s.as_ref();
+        let mut start = Some(0);
+        while let Some(from) = start{
+            let next = s[from..].chars().next().map(|c| from + c.len_utf8());
+            start = match self.longest_match_at(s,from){
+                Some(end) if !f(from,end) => None,
+                Some(end) if end > from => Some(end),
+                _ => next
+            };
+        }
+    }
+
+    // every match from left to right. matches are the longest ones starting
+    // leftmost and do not overlap; after an empty match the search resumes
+    // one char later.
+    pub fn find_iter<'a>(&'a self,s: &'a str) -> Matches<'a>{
+        self.find_iter_min_len(s,0)
+    }
+
+    // like find_iter, but only the matches of at least min chars. when the
+    // match at an offset is too short, the search goes on from the next char,
+    // since a match starting inside it may still be long enough.
+    pub fn find_iter_min_len<'a>(&'a self,s: &'a str,min: usize) -> Matches<'a>{
+        Matches{ engine: self, s, positions: PositionMap::new(s), start: Some(0), min_len: min }
+    }
+
+    // s with every match of find_iter replaced by replacement, taken literally
+    pub fn replace_all(&self,s: &str,replacement: &str) -> String{
+        if replacement.is_empty(){
+            return self.delete_all(s);
+        }
+        self.replace_all_count(s,replacement).0
+    }
+
+    // s without the matches of find_iter, that is replace_all with an empty
+    // replacement. only the spans between the matches are copied.
+    pub fn delete_all(&self,s: &str) -> String{
+        let mut ret = String::with_capacity(s.len());
+        let mut copied = 0;
+        for (start,end) in self.find_iter(s){
+            if start > copied{
+                ret.push_str(&s[copied..start]);
+            }
+            copied = end;
+        }
+        ret.push_str(&s[copied..]);
+        ret
+    }
+
+    // replace_all, along with the number of replacements made
+    pub fn replace_all_count(&self,s: &str,replacement: &str) -> (String,usize){
+        let mut ret = String::with_capacity(s.len());
+        let mut copied = 0;
+        let mut count = 0;
+        for (start,end) in self.find_iter(s){
+            ret.push_str(&s[copied..start]);
+            ret.push_str(replacement);
+            copied = end;
+            count += 1;
+        }
+        ret.push_str(&s[copied..]);
+        (ret,count)
+    }
+
+    // what is left of s after the longest match anchored at its start,
+    // or None if no prefix of s matches
+    pub fn match_prefix_remainder<'a>(&self,s: &'a str) -> Option<&'a str>{
+        self.longest_match_at(s,0).map(|end| &s[end..])
+    }
+
+    // end offset of the longest match starting at byte offset start, if any.
+    // `^` only holds at offset 0 and `$` at the end of s.
+    pub fn longest_match_at(&self,s: &str,start: usize) -> Option<usize>{
+        let mut current = if start == 0 { self.input_start } else { self.start };
+        let accepts = |current: usize,offset: usize| {
+            if offset == s.len() { self.end_acceptors[current] } else { self.acceptors[current] }
+        };
+        let mut end = if accepts(current,start) { Some(start) } else { None };
+        for (i,c) in s[start..].char_indices(){
+            match self.step(current,c){
+                None => { break; },
+                Some(to) => { current = to; }
+            }
+            let offset = start + i + c.len_utf8();
+            if accepts(current,offset){
+                end = Some(offset);
+            }
+        }
+        end
+    }
+
+    // like match_string, but over a fallible source of chars.
+    // the first Err aborts matching and is handed back to the caller,
+    // unless the automaton has already rejected the input by then.
+    pub fn match_results<I,E>(&self,iter: I) -> Result<bool,E>
+        where I: IntoIterator<Item = Result<char,E>>{
+        let mut current = self.input_start;
+        for c in iter{
+            let c = c?;
+            match self.step(current,c){
+                None => { return Ok(false); },
+                Some(to) => { current = to; }
+            }
+        }
+        Ok(self.end_acceptors[current])
+    }
+}
+
+// states that cannot reach an acceptor, found by walking the edges backwards
+fn dead_states(edges: &[HashMap<char,usize>],ranges: &[Vec<(char,char,usize)>],others: &[Option<usize>],end_acceptors: &[bool]) -> Vec<bool>{
+    let mut predecessors: Vec<Vec<usize>> = vec![vec![]; edges.len()];
+    for (from,map) in edges.iter().enumerate(){
+        let range_targets = ranges[from].iter().map(|&(_,_,to)| to);
+        for to in map.values().cloned().chain(range_targets).chain(others[from]){
+            predecessors[to].push(from);
+        }
+    }
+    let mut dead: Vec<bool> = end_acceptors.iter().map(|&accepts| !accepts).collect();
+    let mut stack: Vec<usize> = (0..edges.len()).filter(|&state| end_acceptors[state]).collect();
+    while let Some(state) = stack.pop(){
+        for &from in &predecessors[state]{
+            if dead[from]{
+                dead[from] = false;
+                stack.push(from);
+            }
+        }
+    }
+    dead
+}
+
+// iterator over the matches in a string, see Engine::find_iter
+pub struct Matches<'a>{
+    engine: &'a Engine,
+    s: &'a str,
+    positions: PositionMap,
+    // char step the next search starts at, None once past the end
+    start: Option<usize>,
+    // in chars, see Engine::find_iter_min_len
+    min_len: usize
+}
+
+impl<'a> Iterator for Matches<'a>{
+    type Item = (usize,usize);
+
+    fn next(&mut self) -> Option<(usize,usize)>{
+        while let Some(start) = self.start{
+            let next = if start < self.positions.last_step() { Some(start + 1) } else { None };
+            let end = self.engine.longest_match_at(self.s,self.positions.byte(start))
+                .map(|end| self.positions.step(end))
+                .filter(|&end| end - start >= self.min_len);
+            if let Some(end) = end{
+                self.start = if end > start { Some(end) } else { next };
+                return Some(self.positions.bytes((start,end)));
+            }
+            self.start = next;
+        }
+        None
+    }
+}
+
+
+// matches by simulating the NFA directly on the set of active nodes.
+// there is no subset construction up front, at the price of slower matching.
+pub struct NfaEngine{
+    graph: Graph
+}
+
+impl NfaEngine{
+    pub fn new(graph: Graph) -> NfaEngine{
+        NfaEngine{ graph }
+    }
+
+    pub fn match_string<S: AsRef<str>>(&self,s: S) -> bool{
+        if s.as_ref().is_empty(){
+            return self.graph.accepts_empty();
+        }
+        let mut current = self.graph.start_nodes();
+        for c in s.as_ref().chars(){
+            current = self.graph.step(&current,c);
+            if current.is_empty(){
+                return false;
+            }
+        }
+        self.graph.accepts_at_end(&current)
+    }
+
+    // the byte spans of the capture groups when all of s matches, see Graph::captures
+    pub fn captures(&self,s: &str) -> Option<GroupSpans>{
+        self.graph.captures(s)
+    }
+
+    // like captures, for a match of a span of haystack, see Graph::captures_at
+    pub fn captures_at(&self,haystack: &str,span: (usize,usize)) -> Option<GroupSpans>{
+        self.graph.captures_at(haystack,span)
+    }
+
+    // captures_at for every span, see Graph::captures_spans
+    pub fn captures_spans<I: IntoIterator<Item = (usize,usize)>>(&self,haystack: &str,spans: I) -> Vec<Option<GroupSpans>>{
+        self.graph.captures_spans(haystack,spans)
+    }
+}
+
+
+// like NfaEngine, for a graph from build_nfa_with_counters. the active nodes
+// carry the counts of the repetitions they are in, so a{5000} takes a handful
+// of nodes instead of thousands, but there are no captures.
+pub struct CountedNfaEngine{
+    graph: CountedGraph
+}
+
+impl CountedNfaEngine{
+    pub fn new(graph: CountedGraph) -> CountedNfaEngine{
+        CountedNfaEngine{ graph }
+    }
+
+    // parses pattern and counts every repetition counted up to at least
+    // threshold, see build_nfa_with_counters
+    pub fn compile(pattern: &str,threshold: usize) -> Result<CountedNfaEngine,ParseError>{
+        let expr = parse(&mut pattern.chars())?;
+        Ok(CountedNfaEngine::new(build_nfa_with_counters(&expr,&mut NodeAllocator::new(),threshold)))
+    }
+
+    pub fn match_string<S: AsRef<str>>(&self,s: S) -> bool{
+        if s.as_ref().is_empty(){
+            return self.graph.accepts_empty();
+        }
+        let mut current = self.graph.start_states();
+        for c in s.as_ref().chars(){
+            current = self.graph.step(&current,c);
+            if current.is_empty(){
+                return false;
+            }
+        }
+        self.graph.accepts_at_end(&current)
+    }
+}
+
+
+// memoizes match_string verdicts for the most recently queried inputs
+pub struct CachedEngine{
+    engine: Engine,
+    capacity: usize,
+    // the verdict for each input, and when it was last queried
+    cache: HashMap<Rc<str>,(bool,u64)>,
+    // every query by its time, oldest first. an entry is stale once its input
+    // has been queried again, and is skipped when the oldest input is evicted.
+    order: VecDeque<(u64,Rc<str>)>,
+    clock: u64,
+    hits: usize,
+    misses: usize
+}
+
+impl CachedEngine{
+    pub fn new(engine: Engine,capacity: usize) -> CachedEngine{
+        CachedEngine{
+            engine,
+            capacity,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    pub fn match_string<S: AsRef<str>>(&mut self,s: S) -> bool{
+        let s = s.as_ref();
+        self.clock += 1;
+        if let Some((key,&(verdict,_))) = self.cache.get_key_value(s){
+            let key = key.clone();
+            self.hits += 1;
+            self.cache.get_mut(s).unwrap().1 = self.clock;
+            self.order.push_back((self.clock,key));
+            self.drop_stale();
+            return verdict;
+        }
+
+        self.misses += 1;
+        let verdict = self.engine.match_string(s);
+        if self.capacity > 0{
+            if self.cache.len() == self.capacity{
+                while let Some((used,key)) = self.order.pop_front(){
+                    if self.cache[&key].1 == used{
+                        self.cache.remove(&key);
+                        break;
+                    }
                 }
             }
+            let key: Rc<str> = Rc::from(s);
+            self.cache.insert(key.clone(),(verdict,self.clock));
+            self.order.push_back((self.clock,key));
+        }
+        verdict
+    }
+
+    // keeps order from growing past twice the capacity, so that each query
+    // costs O(1) on average
+    fn drop_stale(&mut self){
+        if self.order.len() > 2 * self.capacity{
+            let cache = &self.cache;
+            self.order.retain(|&(used,ref key)| cache[key].1 == used);
         }
     }
+
+    // number of queries answered from the cache
+    pub fn hits(&self) -> usize{
+        self.hits
+    }
+
+    // number of queries that had to run the automaton
+    pub fn misses(&self) -> usize{
+        self.misses
+    }
 }
 
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use automaton::build_dfa;
+    use automaton::build_nfa;
+
+    fn nfa(pattern: &str) -> Graph{
+        build_nfa(&parse(&mut pattern.chars()).unwrap(),&mut NodeAllocator::new())
+    }
+
+    fn engine(pattern: &str) -> Engine{
+        Engine::new(build_dfa(&nfa(pattern)))
+    }
+
+    const PATTERNS: &[&str] = &["(ab)*c","a|b|","[^a]b*","a{2,3}","^a|b$","(a|b)*abb",".a+","x(^a|b)","a(a|)","[a-c]{2}",
+                                "a|b|c|d|e|f|g|h|i|j|k"];
+    const INPUTS: &[&str] = &["","a","b","c","ab","abc","ababc","aa","aaa","aaaa","ba","xb","xa","abb","babb","zaa","ac",
+                              "ca","k","kk"];
+
+    #[test]
+    fn empty_pattern(){
+        let engine = engine("");
+        assert!(engine.match_string(""));
+        assert!(!engine.match_string("x"));
+        assert_eq!(engine.find("x"),Some((0,0)));
+    }
+
+    // text split into chunks, as a rope holds it
+    struct Chunks<'a>(&'a [&'a str]);
+
+    impl<'a> TextSource for Chunks<'a>{
+        type Chars = Box<dyn Iterator<Item = (usize,char)> + 'a>;
+
+        fn char_indices(self) -> Self::Chars{
+            let mut offset = 0;
+            Box::new(self.0.iter().flat_map(move |chunk| {
+                let start = offset;
+                offset += chunk.len();
+                chunk.char_indices().map(move |(i,c)| (start + i,c))
+            }))
+        }
+    }
+
+    #[test]
+    fn match_source_across_chunks(){
+        let engine = engine("ab+c");
+        assert!(engine.match_source(Chunks(&["a","bb","bc"])));
+        assert!(engine.match_source(Chunks(&["ab","","c"])));
+        assert!(!engine.match_source(Chunks(&["ab","cc"])));
+        assert!(self::engine("é.").match_source(Chunks(&["é","x"])));
+        assert_eq!(Chunks(&["aé","b"]).char_indices().collect::<Vec<_>>(),vec![(0,'a'),(1,'é'),(3,'b')]);
+    }
+
+    #[test]
+    fn for_each_match(){
+        let engine = engine("a+|é");
+        let mut count = 0;
+        engine.for_each_match("aabéxa",|_,_| { count += 1; true });
+        assert_eq!(count,3);
+        let mut spans = [(0,0); 2];
+        let mut found = 0;
+        engine.for_each_match("aabéxa",|start,end| {
+            spans[found] = (start,end);
+            found += 1;
+            found < 2
+        });
+        assert_eq!((found,spans),(2,[(0,2),(3,5)]));
+        let mut empty = 0;
+        self::engine("b*").for_each_match("ab",|_,_| { empty += 1; true });
+        assert_eq!(empty,self::engine("b*").find_iter("ab").count());
+    }
+
+    #[test]
+    fn edgeless_dfa(){
+        let dfa = DFA::from_json("{\"states\":[0],\"transitions\":[],\"start\":0,\"accepting\":[0]}").unwrap();
+        let engine = Engine::new(dfa);
+        assert!(engine.match_string(""));
+        assert!(!engine.match_string("x"));
+    }
+
+    #[test]
+    fn try_new(){
+        let json = "{\"states\":[0,1,2],\"start\":0,\"accepting\":[1],\"transitions\":[{\"from\":0,\"to\":1,\
+                    \"condition\":\"a\"},{\"from\":0,\"to\":2,\"condition\":\"a\"}]}";
+        assert!(Engine::try_new(DFA::from_json(json).unwrap()).is_err());
+        assert!(Engine::try_new(build_dfa(&nfa("(ab)*|a."))).unwrap().match_string("ax"));
+    }
+
+    #[test]
+    fn nfa_engine_agrees_with_dfa(){
+        for pattern in PATTERNS{
+            let (dfa,nfa) = (engine(pattern),NfaEngine::new(nfa(pattern)));
+            for input in INPUTS{
+                assert_eq!(dfa.match_string(input),nfa.match_string(input),"{:?} on {:?}",pattern,input);
+            }
+        }
+    }
+
+    #[test]
+    fn counters_agree_with_unrolling(){
+        let counted = ["(a{2}){2}","(a?){3}","(ab|a){2,}","x(a|b){3}y","(a{2})*","a{0,2}b","(a{2,3})+","(^a|b){2}","a{2}$"];
+        for pattern in PATTERNS.iter().chain(&counted){
+            let expr = parse(&mut pattern.chars()).unwrap();
+            let counters = CountedNfaEngine::new(build_nfa_with_counters(&expr,&mut NodeAllocator::new(),0));
+            let dfa = engine(pattern);
+            for input in INPUTS.iter().chain(&["aaaaa","aaaaaa","xaby","xababy","abaa","ab","bb","aab"]){
+                assert_eq!(dfa.match_string(input),counters.match_string(input),"{:?} on {:?}",pattern,input);
+            }
+        }
+    }
+
+    #[test]
+    fn counters_do_not_unroll(){
+        let expr = parse(&mut "a{5000}".chars()).unwrap();
+        let graph = build_nfa_with_counters(&expr,&mut NodeAllocator::new(),1000);
+        assert!(graph.node_count() < 10);
+        let engine = CountedNfaEngine::new(graph);
+        assert!(engine.match_string("a".repeat(5000)));
+        assert!(!engine.match_string("a".repeat(4999)));
+        assert!(!engine.match_string("a".repeat(5001)));
+
+        let engine = CountedNfaEngine::compile("(ab){3000,}c",1000).unwrap();
+        assert!(engine.match_string("ab".repeat(3001) + "c"));
+        assert!(!engine.match_string("ab".repeat(2999) + "c"));
+    }
+
+    #[test]
+    fn anchors_in_any_order_on_empty_input(){
+        for pattern in &["$^","^$","($|a)^","(^|$)*$^b*"]{
+            assert!(engine(pattern).match_string(""),"{:?}",pattern);
+            assert!(NfaEngine::new(nfa(pattern)).match_string(""),"{:?}",pattern);
+            assert!(NfaEngine::new(nfa(pattern)).captures("").is_some(),"{:?}",pattern);
+            assert!(!engine(pattern).match_string("a"),"{:?}",pattern);
+        }
+        assert_eq!(engine("$^").find("a"),None);
+        assert_eq!(engine("$^").find_iter("").collect::<Vec<_>>(),vec![(0,0)]);
+        assert!(!engine("$^a").match_string("a"));
+        assert!(engine("^a").match_string("a"));
+        assert!(!engine("^a").match_string("ba"));
+        assert!(!engine("a$").match_string("ab"));
+    }
+
+    #[test]
+    fn sparse_and_dense_agree(){
+        let map: HashMap<char,usize> = "abcdefghijk".chars().enumerate().map(|(i,c)| (c,i)).collect();
+        let dense = Transitions::Dense(map.clone());
+        let mut sparse: Vec<(char,usize)> = map.into_iter().collect();
+        sparse.sort();
+        let sparse = Transitions::Sparse(sparse);
+        for c in "`abcdefghijklあ".chars(){
+            assert_eq!(sparse.get(c),dense.get(c),"{:?}",c);
+        }
+    }
+
+    #[test]
+    fn captures(){
+        let engine = NfaEngine::new(nfa("(a+)(b+)"));
+        assert_eq!(engine.captures("aaabb"),Some(vec![Some((0,5)),Some((0,3)),Some((3,5))]));
+        // the last iteration wins, a group that did not take part is None
+        let engine = NfaEngine::new(nfa("((a)|b)*"));
+        assert_eq!(engine.captures("ab"),Some(vec![Some((0,2)),Some((1,2)),Some((0,1))]));
+        assert_eq!(engine.captures("b"),Some(vec![Some((0,1)),Some((0,1)),None]));
+        assert_eq!(engine.captures("c"),None);
+    }
+
+    #[test]
+    fn find(){
+        assert_eq!(engine("ab").find("xxabxx"),Some((2,4)));
+        assert_eq!(engine("ab").find("xxaxx"),None);
+        assert_eq!(engine("a+").find("baaab"),Some((1,4)));
+        assert_eq!(engine("b$").find("bab"),Some((2,3)));
+    }
+
+    #[test]
+    fn find_char_indices(){
+        assert_eq!(engine("b").find_char_indices("あb"),Some((1,2)));
+    }
+
+    #[test]
+    fn find_iter(){
+        assert_eq!(engine("a*").find_iter("aabaa").collect::<Vec<_>>(),vec![(0,2),(2,2),(3,5),(5,5)]);
+        assert_eq!(engine("ab").find_iter("ababxab").collect::<Vec<_>>(),vec![(0,2),(2,4),(5,7)]);
+        assert_eq!(engine("x*").find_iter("あ").collect::<Vec<_>>(),vec![(0,0),(3,3)]);
+        assert_eq!(engine("^a").find_iter("aaa").collect::<Vec<_>>(),vec![(0,1)]);
+    }
+
+    #[test]
+    fn rfind(){
+        assert_eq!(engine("a").rfind("aba"),Some((2,3)));
+        assert_eq!(engine("a*").rfind("aab"),Some((3,3)));
+        assert_eq!(engine("ba*|a").rfind("baac"),Some((0,3)));
+        assert_eq!(engine("^a").rfind("aba"),Some((0,1)));
+        assert_eq!(engine("(^a|b)").rfind("aba"),Some((1,2)));
+        assert_eq!(engine("あ+").rfind("xあああy"),Some((1,10)));
+        assert_eq!(engine("ab*").rfind("xabab"),Some((3,5)));
+        assert_eq!(engine("a$").rfind("aab"),None);
+        assert_eq!(engine("c").rfind("aba"),None);
+    }
+
+    #[test]
+    fn replace_all(){
+        assert_eq!(engine("a+").replace_all("xaaxax","Z"),"xZxZx");
+        assert_eq!(engine("a+").replace_all("xyz","Z"),"xyz");
+    }
+
+    #[test]
+    fn replace_all_count(){
+        assert_eq!(engine("a+").replace_all_count("xaaxax","Z"),("xZxZx".to_owned(),2));
+        assert_eq!(engine("a+").replace_all_count("xyz","Z"),("xyz".to_owned(),0));
+    }
+
+    #[test]
+    fn delete_all(){
+        assert_eq!(engine("[0-9]").delete_all("a1b2c3"),"abc");
+        assert_eq!(engine("[0-9]").delete_all("12a34"),"a");
+        assert_eq!(engine("[0-9]*").delete_all("a12b"),"ab");
+        assert_eq!(engine("x*").delete_all("abc"),"abc");
+        assert_eq!(engine("a").delete_all("aaa"),"");
+        assert_eq!(engine("a").replace_all("bab",""),"bb");
+    }
+
+    #[test]
+    fn run_stops_in_dead_state(){
+        let result = engine("ab").run("acbbb");
+        assert_eq!(result,MatchResult{ accepted: false, consumed: 2, ended_in_dead_state: true });
+        assert!(engine("ab").run("ab").accepted);
+    }
+
+    #[test]
+    fn match_results(){
+        let engine = engine("(ab)*");
+        assert_eq!(engine.match_results(vec![Ok('a'),Ok('b')]),Ok::<bool,u8>(true));
+        assert_eq!(engine.match_results(vec![Ok('a'),Err(3u8),Ok('b')]),Err(3));
+        assert_eq!(engine.match_results(vec![Ok('b'),Err(3u8)]),Ok(false));
+    }
+
+    #[test]
+    fn match_bytes(){
+        assert!(engine(".*").match_bytes("あ".as_bytes()));
+        assert!(!engine(".*").match_bytes(&[0x61,0xff]));
+    }
+
+    #[test]
+    fn cached_engine(){
+        let mut cached = CachedEngine::new(engine("(ab)*"),2);
+        assert!(cached.match_string("ab"));
+        assert!(cached.match_string("ab"));
+        assert_eq!((cached.hits(),cached.misses()),(1,1));
+        assert!(!cached.match_string("a"));
+        assert!(cached.match_string("abab"));
+        // "ab" was evicted by the two newer inputs
+        assert!(cached.match_string("ab"));
+        assert_eq!((cached.hits(),cached.misses()),(1,4));
+    }
+
+    #[test]
+    fn cached_engine_evicts_least_recently_used(){
+        let mut cached = CachedEngine::new(engine("a*"),2);
+        cached.match_string("a");
+        cached.match_string("b");
+        // many hits on "a" make "b" the least recently used
+        for _ in 0..10{
+            cached.match_string("a");
+        }
+        assert!(cached.order.len() <= 4);
+        cached.match_string("aa");
+        cached.match_string("a");
+        assert_eq!((cached.hits(),cached.misses()),(11,3));
+        cached.match_string("b");
+        assert_eq!(cached.misses(),4);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip(){
+        use serde_json;
+
+        for pattern in PATTERNS{
+            let engine = engine(pattern);
+            let loaded: Engine = serde_json::from_str(&serde_json::to_string(&engine).unwrap()).unwrap();
+            for input in INPUTS{
+                assert_eq!(engine.match_string(input),loaded.match_string(input),"{:?} on {:?}",pattern,input);
+            }
+        }
+    }
+}
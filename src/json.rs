@@ -0,0 +1,194 @@
+// just enough JSON to read back what the exporters write
+
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug,PartialEq,Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match *self {
+            Value::Array(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as usize),
+            _ => None,
+        }
+    }
+}
+
+pub fn escape(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+pub fn parse(input: &str) -> Result<Value, String> {
+    let mut input = input.chars().peekable();
+    let value = value(&mut input)?;
+    skip_whitespace(&mut input);
+    match input.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected `{}` after value", c)),
+    }
+}
+
+fn skip_whitespace(input: &mut Peekable<Chars>) {
+    while let Some(&c) = input.peek() {
+        if !c.is_whitespace() {
+            break;
+        }
+        input.next();
+    }
+}
+
+fn expect(input: &mut Peekable<Chars>, expected: char) -> Result<(), String> {
+    skip_whitespace(input);
+    match input.next() {
+        Some(c) if c == expected => Ok(()),
+        Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+        None => Err(format!("expected `{}`, found end of input", expected)),
+    }
+}
+
+fn keyword(input: &mut Peekable<Chars>, word: &str, value: Value) -> Result<Value, String> {
+    for expected in word.chars() {
+        if input.next() != Some(expected) {
+            return Err(format!("invalid literal, expected `{}`", word));
+        }
+    }
+    Ok(value)
+}
+
+fn value(input: &mut Peekable<Chars>) -> Result<Value, String> {
+    skip_whitespace(input);
+    match input.peek().cloned() {
+        Some('n') => keyword(input, "null", Value::Null),
+        Some('t') => keyword(input, "true", Value::Bool(true)),
+        Some('f') => keyword(input, "false", Value::Bool(false)),
+        Some('"') => string(input).map(Value::String),
+        Some('[') => {
+            input.next();
+            let mut v = vec![];
+            skip_whitespace(input);
+            if input.peek() == Some(&']') {
+                input.next();
+                return Ok(Value::Array(v));
+            }
+            loop {
+                v.push(value(input)?);
+                skip_whitespace(input);
+                match input.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Value::Array(v)),
+                    _ => return Err("unterminated array".to_owned()),
+                }
+            }
+        }
+        Some('{') => {
+            input.next();
+            let mut map = BTreeMap::new();
+            skip_whitespace(input);
+            if input.peek() == Some(&'}') {
+                input.next();
+                return Ok(Value::Object(map));
+            }
+            loop {
+                skip_whitespace(input);
+                let key = string(input)?;
+                expect(input, ':')?;
+                map.insert(key, value(input)?);
+                skip_whitespace(input);
+                match input.next() {
+                    Some(',') => {}
+                    Some('}') => return Ok(Value::Object(map)),
+                    _ => return Err("unterminated object".to_owned()),
+                }
+            }
+        }
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut buffer = String::new();
+            while let Some(&c) = input.peek() {
+                if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                    break;
+                }
+                buffer.push(c);
+                input.next();
+            }
+            buffer.parse().map(Value::Number).map_err(|_| format!("invalid number `{}`", buffer))
+        }
+        Some(c) => Err(format!("unexpected `{}`", c)),
+        None => Err("unexpected end of input".to_owned()),
+    }
+}
+
+fn string(input: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(input, '"')?;
+    let mut ret = String::new();
+    loop {
+        match input.next() {
+            Some('"') => return Ok(ret),
+            Some('\\') => {
+                let c = match input.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let code: String = input.take(4).collect();
+                        u32::from_str_radix(&code, 16)
+                            .ok()
+                            .and_then(::std::char::from_u32)
+                            .ok_or_else(|| format!("invalid escape `\\u{}`", code))?
+                    }
+                    _ => return Err("invalid escape".to_owned()),
+                };
+                ret.push(c);
+            }
+            Some(c) => ret.push(c),
+            None => return Err("unterminated string".to_owned()),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "unicode")]
+extern crate unicode_general_category;
+#[cfg(feature = "unicode")]
+extern crate unicode_script;
+#[cfg(feature = "unicode-width")]
+extern crate unicode_width;
+
+pub mod parser;
+pub mod automaton;
+pub mod engine;
+mod json;
+mod positions;
+mod ranges;
+mod regex;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "unicode")]
+mod unicode;
+
+pub use engine::CountedNfaEngine;
+pub use parser::ParseError;
+pub use parser::SyntaxConfig;
+pub use regex::BytesRegex;
+pub use regex::Captures;
+pub use regex::CompileMetrics;
+pub use regex::ForbidEmptyError;
+pub use regex::LanguageDiff;
+pub use regex::Match;
+pub use regex::MultiRegex;
+pub use regex::Regex;
+pub use regex::RegexMetrics;
+pub use regex::RuleError;
+pub use regex::Strategy;
+pub use regex::Warning;
@@ -1,13 +1,10 @@
-mod parser;
-use parser::parse;
+extern crate regexpr;
 
-mod automaton;
-use automaton::NodeAllocator;
-use automaton::build_nfa;
-use automaton::build_dfa;
-
-mod engine;
-use engine::Engine;
+use regexpr::parser::parse;
+use regexpr::automaton::NodeAllocator;
+use regexpr::automaton::build_nfa;
+use regexpr::automaton::build_dfa;
+use regexpr::engine::Engine;
 
 fn main() {
     use std::fs::File;
@@ -1,20 +1,58 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 use std::iter::{Iterator, Peekable};
+use std::sync::OnceLock;
 
+use ranges;
+use ranges::Utf8Sequence;
+#[cfg(feature = "unicode")]
+use unicode::Property;
+
+#[derive(PartialEq,Eq,Hash,Clone)]
 pub enum RegExpr {
     Character(char),
+    Any,
+    // zero-width assertions for the start and the end of the input
+    StartAnchor,
+    EndAnchor,
     Range(Vec<char>),
+    NegatedRange(Vec<char>),
+    // the chars in any of the inclusive ranges, which are sorted and disjoint.
+    // classes too large to list char by char, like `\p{L}`, are kept this way.
+    Ranges(Vec<(char, char)>),
     Repeation(Box<RegExpr>),
+    Plus(Box<RegExpr>),
+    // between min and max repetitions, unbounded if max is None
+    Repeat {
+        expr: Box<RegExpr>,
+        min: usize,
+        max: Option<usize>,
+    },
     Branch(Box<RegExpr>, Box<RegExpr>),
     Sequence(Vec<RegExpr>),
+    // a parenthesized expression, capture groups are numbered from 1 in the
+    // order of their `(`
+    Group(Box<RegExpr>, usize),
 }
 
 impl fmt::Debug for RegExpr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             RegExpr::Character(ref c) => write!(f, "{}", c),
+            RegExpr::Any => write!(f, "."),
+            RegExpr::StartAnchor => write!(f, "^"),
+            RegExpr::EndAnchor => write!(f, "$"),
             RegExpr::Range(ref range) => write!(f, "({:?})", range),
+            RegExpr::NegatedRange(ref range) => write!(f, "(^{:?})", range),
+            RegExpr::Ranges(ref ranges) => write!(f, "({:?})", ranges),
             RegExpr::Repeation(ref expr) => write!(f, "({:?}*)", expr),
+            RegExpr::Plus(ref expr) => write!(f, "({:?}+)", expr),
+            RegExpr::Repeat { ref expr, min, max: Some(max) } => write!(f, "({:?}{{{},{}}})", expr, min, max),
+            RegExpr::Repeat { ref expr, min, max: None } => write!(f, "({:?}{{{},}})", expr, min),
             RegExpr::Branch(ref lhs, ref rhs) => write!(f, "({:?}|{:?})", lhs, rhs),
             RegExpr::Sequence(ref v) => {
                 try!(write!(f, "("));
@@ -23,6 +61,7 @@ impl fmt::Debug for RegExpr {
                 }
                 write!(f, ")")
             }
+            RegExpr::Group(ref expr, _) => write!(f, "({:?})", expr),
         }
     }
 }
@@ -42,68 +81,733 @@ impl RegExpr {
             }
         }
     }
+
+    // the same expression with every char also matching its other cases:
+    // characters become ranges of their case variants, and classes include
+    // the variants of their members. chars without case are left alone.
+    pub fn case_insensitive(&self) -> RegExpr {
+        let fold = |v: &[char]| {
+            let mut folded: Vec<char> = v.iter().flat_map(|&c| case_variants(c)).collect();
+            folded.sort();
+            folded.dedup();
+            folded
+        };
+        match *self {
+            RegExpr::Character(c) => {
+                let variants = case_variants(c);
+                if variants.len() == 1 {
+                    RegExpr::Character(c)
+                } else {
+                    RegExpr::Range(variants)
+                }
+            }
+            RegExpr::Range(ref v) => RegExpr::Range(fold(v)),
+            RegExpr::NegatedRange(ref v) => RegExpr::NegatedRange(fold(v)),
+            RegExpr::Ranges(ref v) => {
+                // only the chars with other cases can add anything
+                let mut folded = v.clone();
+                for group in case_groups().values() {
+                    if group.iter().any(|&c| ranges::contains(v, c)) {
+                        folded.extend(group.iter().map(|&c| (c, c)));
+                    }
+                }
+                RegExpr::Ranges(ranges::normalize(folded))
+            }
+            RegExpr::Repeation(ref expr) => RegExpr::Repeation(Box::new(expr.case_insensitive())),
+            RegExpr::Plus(ref expr) => RegExpr::Plus(Box::new(expr.case_insensitive())),
+            RegExpr::Repeat { ref expr, min, max } => {
+                RegExpr::Repeat {
+                    expr: Box::new(expr.case_insensitive()),
+                    min,
+                    max,
+                }
+            }
+            RegExpr::Branch(ref lhs, ref rhs) => {
+                RegExpr::Branch(Box::new(lhs.case_insensitive()), Box::new(rhs.case_insensitive()))
+            }
+            RegExpr::Sequence(ref v) => RegExpr::Sequence(v.iter().map(|e| e.case_insensitive()).collect()),
+            RegExpr::Group(ref expr, group) => RegExpr::Group(Box::new(expr.case_insensitive()), group),
+            ref expr => expr.clone(),
+        }
+    }
+
+    // numbers the groups left to right, starting after next. parsing runs
+    // backwards over the tokens, so the numbers are only known afterwards.
+    fn number_groups(&mut self, next: &mut usize) {
+        match *self {
+            RegExpr::Group(ref mut expr, ref mut group) => {
+                *next += 1;
+                *group = *next;
+                expr.number_groups(next);
+            }
+            RegExpr::Repeation(ref mut expr) |
+            RegExpr::Plus(ref mut expr) |
+            RegExpr::Repeat { ref mut expr, .. } => expr.number_groups(next),
+            RegExpr::Branch(ref mut lhs, ref mut rhs) => {
+                lhs.number_groups(next);
+                rhs.number_groups(next);
+            }
+            RegExpr::Sequence(ref mut v) => {
+                for expr in v {
+                    expr.number_groups(next);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // the same expression over an alphabet of only the chars in universe:
+    // `.` and negated classes match the chars of universe they would match
+    // otherwise, instead of all of Unicode. chars named in the pattern are
+    // left alone.
+    pub fn within(&self, universe: &BTreeSet<char>) -> RegExpr {
+        match *self {
+            RegExpr::Any => RegExpr::Range(universe.iter().cloned().collect()),
+            RegExpr::NegatedRange(ref v) => RegExpr::Range(universe.iter().cloned().filter(|c| !v.contains(c)).collect()),
+            RegExpr::Ranges(ref v) => {
+                RegExpr::Range(universe.iter().cloned().filter(|&c| ranges::contains(v, c)).collect())
+            }
+            RegExpr::Repeation(ref expr) => RegExpr::Repeation(Box::new(expr.within(universe))),
+            RegExpr::Plus(ref expr) => RegExpr::Plus(Box::new(expr.within(universe))),
+            RegExpr::Repeat { ref expr, min, max } => {
+                RegExpr::Repeat {
+                    expr: Box::new(expr.within(universe)),
+                    min,
+                    max,
+                }
+            }
+            RegExpr::Branch(ref lhs, ref rhs) => {
+                RegExpr::Branch(Box::new(lhs.within(universe)), Box::new(rhs.within(universe)))
+            }
+            RegExpr::Sequence(ref v) => RegExpr::Sequence(v.iter().map(|e| e.within(universe)).collect()),
+            RegExpr::Group(ref expr, group) => RegExpr::Group(Box::new(expr.within(universe)), group),
+            ref expr => expr.clone(),
+        }
+    }
+
+    // the same expression over bytes instead of chars, with each byte
+    // standing for itself as the char of the same value, from U+0000 to
+    // U+00FF. chars are spelled out in UTF-8, and `.` and negated classes
+    // match any single byte but the ASCII chars they leave out, since the
+    // others take more than one byte anyway.
+    pub fn to_bytes(&self) -> RegExpr {
+        let encodings = |ranges: &[(char, char)]| {
+            byte_trie(ranges.iter().flat_map(|&(first, last)| ranges::utf8_sequences(first, last)).collect())
+        };
+        match *self {
+            RegExpr::Character(c) => {
+                RegExpr::Sequence(c.to_string().bytes().map(|byte| RegExpr::Character(byte as char)).collect())
+            }
+            RegExpr::Any => RegExpr::Ranges(vec![('\0', '\u{ff}')]),
+            RegExpr::Range(ref v) => encodings(&ranges::normalize(v.iter().map(|&c| (c, c)).collect())),
+            RegExpr::Ranges(ref v) => encodings(v),
+            RegExpr::NegatedRange(ref v) => {
+                let ascii = ranges::normalize(v.iter().filter(|c| c.is_ascii()).map(|&c| (c, c)).collect());
+                RegExpr::Ranges(ranges::complement(&ascii)
+                    .into_iter()
+                    .filter(|&(first, _)| first <= '\u{ff}')
+                    .map(|(first, last)| (first, cmp::min(last, '\u{ff}')))
+                    .collect())
+            }
+            RegExpr::Repeation(ref expr) => RegExpr::Repeation(Box::new(expr.to_bytes())),
+            RegExpr::Plus(ref expr) => RegExpr::Plus(Box::new(expr.to_bytes())),
+            RegExpr::Repeat { ref expr, min, max } => {
+                RegExpr::Repeat {
+                    expr: Box::new(expr.to_bytes()),
+                    min,
+                    max,
+                }
+            }
+            RegExpr::Branch(ref lhs, ref rhs) => RegExpr::Branch(Box::new(lhs.to_bytes()), Box::new(rhs.to_bytes())),
+            RegExpr::Sequence(ref v) => RegExpr::Sequence(v.iter().map(|e| e.to_bytes()).collect()),
+            RegExpr::Group(ref expr, group) => RegExpr::Group(Box::new(expr.to_bytes()), group),
+            RegExpr::StartAnchor | RegExpr::EndAnchor => self.clone(),
+        }
+    }
+
+    // the alternatives of the outermost `|`, left to right, looking through
+    // groups and one-element sequences around it. without a `|` there, self
+    // is the only alternative.
+    pub fn alternatives(&self) -> Vec<&RegExpr> {
+        match *self {
+            RegExpr::Group(ref expr, _) => expr.alternatives(),
+            RegExpr::Sequence(ref v) if v.len() == 1 => v[0].alternatives(),
+            _ => {
+                let mut alternatives = vec![];
+                self.branches(&mut alternatives);
+                alternatives
+            }
+        }
+    }
+
+    // the parser nests `a|b|c` as ((a|b)|c), some sides inside sequences of one
+    fn branches<'a>(&'a self, alternatives: &mut Vec<&'a RegExpr>) {
+        match *self {
+            RegExpr::Branch(ref lhs, ref rhs) => {
+                lhs.branches(alternatives);
+                rhs.branches(alternatives);
+            }
+            RegExpr::Sequence(ref v) if v.len() == 1 => v[0].branches(alternatives),
+            _ => alternatives.push(self),
+        }
+    }
+
+    // structurally identical subtrees share the same key. the key is the
+    // FNV-1a hash of an encoding of the tree that is fixed here, so it stays
+    // the same across builds and platforms, unlike Hash with DefaultHasher.
+    pub fn canonical_key(&self) -> u64 {
+        let mut key = Fnv::new();
+        self.encode(&mut key);
+        key.0
+    }
+
+    // a tag byte for the variant, then its contents in little endian
+    fn encode(&self, key: &mut Fnv) {
+        let chars = |key: &mut Fnv, v: &[char]| {
+            key.write(&(v.len() as u64).to_le_bytes());
+            for c in v {
+                key.write(&(*c as u32).to_le_bytes());
+            }
+        };
+        match *self {
+            RegExpr::Character(c) => {
+                key.write(&[0]);
+                key.write(&(c as u32).to_le_bytes());
+            }
+            RegExpr::Any => key.write(&[1]),
+            RegExpr::StartAnchor => key.write(&[2]),
+            RegExpr::EndAnchor => key.write(&[3]),
+            RegExpr::Range(ref v) => {
+                key.write(&[4]);
+                chars(key, v);
+            }
+            RegExpr::NegatedRange(ref v) => {
+                key.write(&[5]);
+                chars(key, v);
+            }
+            RegExpr::Ranges(ref v) => {
+                key.write(&[12]);
+                key.write(&(v.len() as u64).to_le_bytes());
+                for &(first, last) in v {
+                    key.write(&(first as u32).to_le_bytes());
+                    key.write(&(last as u32).to_le_bytes());
+                }
+            }
+            RegExpr::Repeation(ref expr) => {
+                key.write(&[6]);
+                expr.encode(key);
+            }
+            RegExpr::Plus(ref expr) => {
+                key.write(&[7]);
+                expr.encode(key);
+            }
+            RegExpr::Repeat { ref expr, min, max } => {
+                key.write(&[8]);
+                key.write(&(min as u64).to_le_bytes());
+                match max {
+                    Some(max) => {
+                        key.write(&[1]);
+                        key.write(&(max as u64).to_le_bytes());
+                    }
+                    None => key.write(&[0]),
+                }
+                expr.encode(key);
+            }
+            RegExpr::Branch(ref lhs, ref rhs) => {
+                key.write(&[9]);
+                lhs.encode(key);
+                rhs.encode(key);
+            }
+            RegExpr::Sequence(ref v) => {
+                key.write(&[10]);
+                key.write(&(v.len() as u64).to_le_bytes());
+                for expr in v {
+                    expr.encode(key);
+                }
+            }
+            RegExpr::Group(ref expr, group) => {
+                key.write(&[11]);
+                key.write(&(group as u64).to_le_bytes());
+                expr.encode(key);
+            }
+        }
+    }
+}
+
+// the 64 bit FNV-1a hash of the bytes written so far
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+// chars equal under simple case folding (upper case, then lower case, when
+// both map to a single char), e.g. `k`, `K` and the Kelvin sign
+fn case_variants(c: char) -> Vec<char> {
+    case_groups().get(&fold_case(c)).cloned().unwrap_or_else(|| vec![c])
+}
+
+// the alternation of sequences of byte ranges, see ranges::utf8_sequences.
+// the sequences are merged as a trie on their first ranges, and the ranges
+// that end sequences at the same point into a single class, which keeps the
+// automata for the many ranges of a class like \p{L} small.
+fn byte_trie(sequences: Vec<Utf8Sequence>) -> RegExpr {
+    let byte = |(first, last): (u8, u8)| (first as char, last as char);
+    // the ranges of the sequences ending here, and the rest of the others by
+    // their first range
+    let mut ends = vec![];
+    let mut tails: BTreeMap<(u8, u8), Vec<Utf8Sequence>> = BTreeMap::new();
+    for mut sequence in sequences {
+        let first = sequence.remove(0);
+        if sequence.is_empty() {
+            ends.push(byte(first));
+        } else {
+            tails.entry(first).or_default().push(sequence);
+        }
+    }
+    let mut alternatives = vec![];
+    if !ends.is_empty() {
+        alternatives.push(RegExpr::Ranges(ranges::normalize(ends)));
+    }
+    for (first, tails) in tails {
+        alternatives.push(RegExpr::Sequence(vec![RegExpr::Ranges(vec![byte(first)]), byte_trie(tails)]));
+    }
+    alternation(alternatives)
+}
+
+// all of v joined by `|`, nested as a balanced tree so that building it does
+// not recurse once per alternative. nothing matches an empty v.
+fn alternation(mut v: Vec<RegExpr>) -> RegExpr {
+    match v.len() {
+        0 => RegExpr::Range(vec![]),
+        1 => v.pop().unwrap(),
+        len => {
+            let rhs = v.split_off(len / 2);
+            RegExpr::Branch(Box::new(alternation(v)), Box::new(alternation(rhs)))
+        }
+    }
+}
+
+// the case_variants of every char that has more than one, by folded char
+fn case_groups() -> &'static HashMap<char, Vec<char>> {
+    static GROUPS: OnceLock<HashMap<char, Vec<char>>> = OnceLock::new();
+    GROUPS.get_or_init(|| {
+        let mut groups: HashMap<char, Vec<char>> = HashMap::new();
+        for c in (0..=0x10ffff).filter_map(char::from_u32) {
+            let folded = fold_case(c);
+            if folded != c {
+                groups.entry(folded).or_insert_with(|| vec![folded]).push(c);
+            }
+        }
+        for group in groups.values_mut() {
+            group.sort();
+        }
+        groups
+    })
 }
 
-#[derive(Debug)]
-pub struct ParseError(u32);
+fn fold_case(c: char) -> char {
+    single(c.to_uppercase()).and_then(|upper| single(upper.to_lowercase())).unwrap_or(c)
+}
+
+// the only char of chars, if there is exactly one
+fn single<I: Iterator<Item = char>>(mut chars: I) -> Option<char> {
+    let c = chars.next()?;
+    match chars.next() {
+        None => Some(c),
+        Some(_) => None,
+    }
+}
+
+// why a pattern was rejected. position is the offset, in chars, of the
+// offending char in the pattern.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ParseError {
+    position: usize,
+    message: String,
+}
+
+impl ParseError {
+    pub(crate) fn new<S: Into<String>>(position: usize, message: S) -> ParseError {
+        ParseError {
+            position,
+            message: message.into(),
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl Error for ParseError {}
+
+// every char the parser can treat as an operator
+const METACHARACTERS: &str = "\\*+?|()[]{}.^$";
+
+// which metacharacters are operators; disabled ones match themselves
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct SyntaxConfig {
+    active: BTreeSet<char>,
+}
+
+impl Default for SyntaxConfig {
+    fn default() -> SyntaxConfig {
+        SyntaxConfig { active: METACHARACTERS.chars().collect() }
+    }
+}
+
+impl SyntaxConfig {
+    pub fn new() -> SyntaxConfig {
+        SyntaxConfig::default()
+    }
+
+    pub fn enable(mut self, c: char) -> SyntaxConfig {
+        if METACHARACTERS.contains(c) {
+            self.active.insert(c);
+        }
+        self
+    }
+
+    pub fn disable(mut self, c: char) -> SyntaxConfig {
+        self.active.remove(&c);
+        self
+    }
 
-fn range<T: Iterator<Item = char>>(input: &mut T) -> Result<RegExpr, ParseError> {
+    // a disabled metacharacter, which has to be read as a literal
+    fn is_disabled(&self, c: char) -> bool {
+        METACHARACTERS.contains(c) && !self.active.contains(&c)
+    }
+}
+
+// a pattern char. escaped chars are never treated as operators.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+enum Token {
+    Plain(char),
+    Escaped(char),
+    // a char given by its code, like `\x41`, which never stands for a class
+    Literal(char),
+    // `\p{..}`, or `\P{..}` when negated
+    #[cfg(feature = "unicode")]
+    Property(Property, bool),
+}
+
+impl Token {
+    fn char(self) -> char {
+        match self {
+            Token::Plain(c) | Token::Escaped(c) | Token::Literal(c) => c,
+            #[cfg(feature = "unicode")]
+            Token::Property(..) => unreachable!("a property class is not a single char"),
+        }
+    }
+
+    // false for tokens standing for a whole class
+    fn is_char(self) -> bool {
+        match self {
+            Token::Plain(_) | Token::Escaped(_) | Token::Literal(_) => true,
+            #[cfg(feature = "unicode")]
+            Token::Property(..) => false,
+        }
+    }
+}
+
+// the chars of the shorthand classes `\d`, `\w` and `\s`, which only cover
+// ASCII, and whether the class is negated, as for `\D`, `\W` and `\S`
+fn perl_class(c: char) -> Option<(Vec<char>, bool)> {
+    let chars: Vec<char> = match c.to_ascii_lowercase() {
+        'd' => ('0'..='9').collect(),
+        'w' => ('0'..='9').chain('A'..='Z').chain(Some('_')).chain('a'..='z').collect(),
+        's' => vec!['\t', '\n', '\u{b}', '\u{c}', '\r', ' '],
+        _ => return None,
+    };
+    Some((chars, c.is_ascii_uppercase()))
+}
+
+// the ASCII chars of a POSIX class like `[:alpha:]`, by its name
+fn posix_class(name: &str) -> Option<Vec<char>> {
+    let belongs: fn(&char) -> bool = match name {
+        "alnum" => |c| c.is_ascii_alphanumeric(),
+        "alpha" => |c| c.is_ascii_alphabetic(),
+        "blank" => |&c| c == ' ' || c == '\t',
+        "cntrl" => |c| c.is_ascii_control(),
+        "digit" => |c| c.is_ascii_digit(),
+        "graph" => |c| c.is_ascii_graphic(),
+        "lower" => |c| c.is_ascii_lowercase(),
+        "print" => |&c| c == ' ' || c.is_ascii_graphic(),
+        "punct" => |c| c.is_ascii_punctuation(),
+        "space" => |&c| c.is_ascii_whitespace() || c == '\u{b}',
+        "upper" => |c| c.is_ascii_uppercase(),
+        "xdigit" => |c| c.is_ascii_hexdigit(),
+        _ => return None,
+    };
+    Some((0..128u8).map(char::from).filter(belongs).collect())
+}
+
+// the name of the `[:name:]` a class has just been read back to, up to its
+// `[`: buffer holds the tokens read so far, last read first, so it has to
+// end in `:name:]` reversed. a `[` without this is the opener of the class.
+fn posix_class_name(buffer: &[(usize, Token)]) -> Option<String> {
+    let mut tokens = buffer.iter().rev().map(|&(_, t)| t);
+    if tokens.next() != Some(Token::Plain(':')) {
+        return None;
+    }
+    let mut name = String::new();
+    loop {
+        match tokens.next() {
+            Some(Token::Plain(':')) if !name.is_empty() => break,
+            Some(Token::Plain(c)) if c.is_ascii_alphabetic() => name.push(c),
+            _ => return None,
+        }
+    }
+    if tokens.next() == Some(Token::Plain(']')) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// tokens come with their offset in the pattern, for error messages
+fn range<T: Iterator<Item = (usize, Token)>>(input: &mut T, close: usize) -> Result<RegExpr, ParseError> {
+    // the class is read backwards, up to its opening `[`
     let mut buffer = Vec::new();
     loop {
         match input.next() {
-            Some(']') => break,
-            Some(c) => buffer.push(c),
-            None => return Err(ParseError(line!())),
+            // the `[` of a `[:name:]` inside the class
+            Some(t @ (_, Token::Plain('['))) if posix_class_name(&buffer).is_some() => buffer.push(t),
+            Some((_, Token::Plain('['))) => break,
+            Some(t) => buffer.push(t),
+            None => return Err(ParseError::new(close, "unmatched `]`")),
         }
     }
-    Ok(RegExpr::Range(buffer))
+    buffer.reverse();
+    let negated = buffer.first().map(|&(_, t)| t) == Some(Token::Plain('^'));
+    if negated {
+        buffer.remove(0);
+    }
+
+    // `a-z` is a range, a `-` at either end of the class is a literal hyphen
+    let mut chars = Vec::new();
+    // the ranges of `\p{..}` classes, which turn the class into a Ranges
+    #[allow(unused_mut)]
+    let mut classes: Vec<(char, char)> = Vec::new();
+    let mut i = 0;
+    while i < buffer.len() {
+        let (position, first) = buffer[i];
+        #[cfg(feature = "unicode")]
+        {
+            if let Token::Property(property, negated) = first {
+                if negated {
+                    return Err(ParseError::new(position, "`\\P` is not supported inside a class"));
+                }
+                classes.extend(property.ranges());
+                i += 1;
+                continue;
+            }
+        }
+        if let Token::Escaped(c) = first {
+            match perl_class(c) {
+                Some((_, true)) => {
+                    let message = format!("`\\{}` is not supported inside a class", c);
+                    return Err(ParseError::new(position, message));
+                }
+                Some((class, false)) => {
+                    chars.extend(class);
+                    i += 1;
+                    continue;
+                }
+                None => {}
+            }
+        }
+        if first == Token::Plain('[') {
+            // only a `[` that was read as the opener of a `[:name:]` is left in the buffer
+            let name: String = buffer[i + 2..].iter().map(|&(_, t)| t.char()).take_while(|&c| c != ':').collect();
+            match posix_class(&name) {
+                Some(class) => chars.extend(class),
+                None => return Err(ParseError::new(position, format!("unknown class `[:{}:]`", name))),
+            }
+            i += name.chars().count() + 4;
+            continue;
+        }
+        let first = first.char();
+        if i + 2 < buffer.len() && buffer[i + 1].1 == Token::Plain('-') && buffer[i + 2].1.is_char() {
+            let last = buffer[i + 2].1.char();
+            if first > last {
+                return Err(ParseError::new(position, format!("invalid range `{}-{}`", first, last)));
+            }
+            chars.extend(first..=last);
+            i += 3;
+        } else {
+            chars.push(first);
+            i += 1;
+        }
+    }
+    if !classes.is_empty() {
+        let ranges = ranges::normalize(classes.into_iter().chain(chars.into_iter().map(|c| (c, c))).collect());
+        Ok(RegExpr::Ranges(if negated { ranges::complement(&ranges) } else { ranges }))
+    } else if negated {
+        Ok(RegExpr::NegatedRange(chars))
+    } else {
+        Ok(RegExpr::Range(chars))
+    }
 }
 
-fn paren<T: Iterator<Item = char>>(input: &mut T) -> Result<RegExpr, ParseError> {
+fn paren<T: Iterator<Item = (usize, Token)>>(input: &mut T, close: usize) -> Result<RegExpr, ParseError> {
     let mut level = 0;
-    let mut buffer: Vec<char> = Vec::new();
+    let mut buffer: Vec<(usize, Token)> = Vec::new();
     loop {
         match input.next() {
-            Some('(') => {
+            Some((i, Token::Plain('('))) => {
                 if level == 0 {
                     break;
                 } else {
                     level -= 1;
-                    buffer.push('(');
+                    buffer.push((i, Token::Plain('(')));
                 }
             }
-            Some(')') => {
+            Some((i, Token::Plain(')'))) => {
                 level += 1;
-                buffer.push(')');
+                buffer.push((i, Token::Plain(')')));
+            }
+            Some(t) => buffer.push(t),
+            None => return Err(ParseError::new(close, "unmatched `)`")),
+        }
+    }
+    branch(&mut buffer.into_iter().peekable()).map(|expr| RegExpr::Group(Box::new(expr), 0))
+}
+
+// the bounds of `{n}`, `{n,}` or `{n,m}`, read backwards up to the `{`,
+// and the position of the `{`
+fn counts<T: Iterator<Item = (usize, Token)>>(input: &mut T,
+                                              close: usize)
+                                              -> Result<((usize, Option<usize>), usize), ParseError> {
+    let mut buffer = Vec::new();
+    let open = loop {
+        match input.next() {
+            Some((i, Token::Plain('{'))) => break i,
+            Some((_, Token::Plain(c))) => buffer.push(c),
+            Some((i, Token::Escaped(c))) | Some((i, Token::Literal(c))) => {
+                return Err(ParseError::new(i, format!("unexpected `\\{}` in a repetition count", c)))
+            }
+            #[cfg(feature = "unicode")]
+            Some((i, Token::Property(..))) => {
+                return Err(ParseError::new(i, "unexpected property class in a repetition count"))
             }
-            Some(c) => buffer.push(c),
-            None => return Err(ParseError(line!())),
+            None => return Err(ParseError::new(close, "unmatched `}`")),
         }
+    };
+    let buffer: String = buffer.into_iter().rev().collect();
+    let invalid = || ParseError::new(open, format!("invalid repetition count `{{{}}}`", buffer));
+    let count = |s: &str| s.parse::<usize>().map_err(|_| invalid());
+    let (min, max) = match buffer.find(',') {
+        None => {
+            let n = count(&buffer)?;
+            (n, Some(n))
+        }
+        Some(i) if i + 1 == buffer.len() => (count(&buffer[..i])?, None),
+        Some(i) => (count(&buffer[..i])?, Some(count(&buffer[i + 1..])?)),
+    };
+    match max {
+        Some(max) if min > max => Err(invalid()),
+        _ => Ok(((min, max), open)),
     }
-    branch(&mut buffer.into_iter().peekable())
 }
 
-fn simple_expr<T: Iterator<Item = char>>(input: &mut T) -> Result<RegExpr, ParseError> {
+// only called with a token left: sequence peeks before asking for an
+// expression, and postfix_expr checks that an operator has an operand
+fn simple_expr<T: Iterator<Item = (usize, Token)>>(input: &mut T) -> Result<RegExpr, ParseError> {
     match input.next() {
-        Some(']') => range(input),
-        Some(')') => paren(input),
-        Some(c) if c != '*' && c != '|' => Ok(RegExpr::Character(c)),
-        _ => Err(ParseError(line!())),
+        Some((i, Token::Plain(']'))) => range(input, i),
+        Some((i, Token::Plain(')'))) => paren(input, i),
+        Some((_, Token::Plain('.'))) => Ok(RegExpr::Any),
+        Some((_, Token::Plain('^'))) => Ok(RegExpr::StartAnchor),
+        Some((_, Token::Plain('$'))) => Ok(RegExpr::EndAnchor),
+        Some((i, Token::Plain(c))) if "[({".contains(c) => Err(ParseError::new(i, format!("unclosed `{}`", c))),
+        Some((i, Token::Plain(c))) if "*+?|}".contains(c) => Err(ParseError::new(i, format!("unexpected `{}`", c))),
+        #[cfg(feature = "unicode")]
+        Some((_, Token::Property(property, false))) => Ok(RegExpr::Ranges(property.ranges())),
+        #[cfg(feature = "unicode")]
+        Some((_, Token::Property(property, true))) => Ok(RegExpr::Ranges(ranges::complement(&property.ranges()))),
+        Some((_, Token::Escaped(c))) => {
+            match perl_class(c) {
+                Some((chars, false)) => Ok(RegExpr::Range(chars)),
+                Some((chars, true)) => Ok(RegExpr::NegatedRange(chars)),
+                None => Ok(RegExpr::Character(c)),
+            }
+        }
+        Some((_, t)) => Ok(RegExpr::Character(t.char())),
+        None => unreachable!(),
     }
 }
 
-fn sequence<T: Iterator<Item = char>>(input: &mut Peekable<T>) -> Result<RegExpr, ParseError> {
+// the input is reversed, so postfix operators come before their operand
+fn postfix_expr<T: Iterator<Item = (usize, Token)>>(input: &mut Peekable<T>) -> Result<RegExpr, ParseError> {
+    let (position, operator) = match input.peek() {
+        Some(&(i, Token::Plain(c))) if "*+?}".contains(c) => (i, c),
+        _ => return simple_expr(input),
+    };
+    input.next();
+    let counts = if operator == '}' { Some(counts(input, position)?) } else { None };
     match input.peek() {
-        None => Err(ParseError(line!())),
-        Some(&'*') => {
-            input.next();
-            Ok(RegExpr::Repeation(Box::new(try!(sequence(input)))))
+        None | Some(&(_, Token::Plain('|'))) => {
+            return Err(match counts {
+                Some((_, open)) => ParseError::new(open, "quantifier without operand"),
+                None => {
+                    let message = format!("dangling `{}` with no preceding expression", operator);
+                    ParseError::new(position, message)
+                }
+            });
+        }
+        Some(_) => {}
+    }
+    let expr = Box::new(postfix_expr(input)?);
+    match counts {
+        None if operator == '*' => Ok(RegExpr::Repeation(expr)),
+        // `x?` is `x{0,1}`
+        None if operator == '?' => {
+            Ok(RegExpr::Repeat {
+                expr,
+                min: 0,
+                max: Some(1),
+            })
         }
-        Some(&'|') => Ok(RegExpr::Sequence(vec![])),
+        None => Ok(RegExpr::Plus(expr)),
+        Some(((min, max), _)) => {
+            Ok(RegExpr::Repeat {
+                expr,
+                min,
+                max,
+            })
+        }
+    }
+}
+
+// an empty sequence, as in an empty pattern, group or alternative, matches
+// only the empty string
+fn sequence<T: Iterator<Item = (usize, Token)>>(input: &mut Peekable<T>) -> Result<RegExpr, ParseError> {
+    match input.peek() {
+        None | Some(&(_, Token::Plain('|'))) => Ok(RegExpr::Sequence(vec![])),
         Some(_) => {
-            let e = try!(simple_expr(input));
+            let e = postfix_expr(input)?;
             if input.peek().is_some() {
-                Ok(RegExpr::concatenated(try!(sequence(input)), e))
+                Ok(RegExpr::concatenated(sequence(input)?, e))
             } else {
                 Ok(e)
             }
@@ -111,18 +815,278 @@ fn sequence<T: Iterator<Item = char>>(input: &mut Peekable<T>) -> Result<RegExpr
     }
 }
 
-fn branch<T: Iterator<Item = char>>(input: &mut Peekable<T>) -> Result<RegExpr, ParseError> {
-    let e = try!(sequence(input));
-    match input.peek() {
+fn branch<T: Iterator<Item = (usize, Token)>>(input: &mut Peekable<T>) -> Result<RegExpr, ParseError> {
+    let e = sequence(input)?;
+    match input.next() {
         None => Ok(e),
-        Some(&'|') => {
-            input.next();
-            Ok(RegExpr::Branch(Box::new(try!(branch(input))), Box::new(e)))
+        Some((_, Token::Plain('|'))) => Ok(RegExpr::Branch(Box::new(branch(input)?), Box::new(e))),
+        Some((i, t)) => Err(ParseError::new(i, format!("unexpected `{}`", t.char()))),
+    }
+}
+
+// the `{name}` after a `\p` or `\P` at position
+#[cfg(feature = "unicode")]
+fn property<T: Iterator<Item = (usize, char)>>(input: &mut T,
+                                               position: usize,
+                                               negated: bool)
+                                               -> Result<Token, ParseError> {
+    let letter = if negated { 'P' } else { 'p' };
+    if input.next().map(|(_, c)| c) != Some('{') {
+        return Err(ParseError::new(position, format!("expected `{{` after `\\{}`", letter)));
+    }
+    let mut name = String::new();
+    loop {
+        match input.next() {
+            Some((_, '}')) => break,
+            Some((_, c)) => name.push(c),
+            None => return Err(ParseError::new(position, format!("unclosed `\\{}{{`", letter))),
+        }
+    }
+    match Property::from_name(&name) {
+        Some(property) => Ok(Token::Property(property, negated)),
+        None => Err(ParseError::new(position, format!("unknown property `{}`", name))),
+    }
+}
+
+// the char of a `\x` escape with two hex digits, or of a `\u` escape
+// with up to six of them in braces, at position
+fn code<T: Iterator<Item = (usize, char)>>(input: &mut T, position: usize, letter: char) -> Result<char, ParseError> {
+    let mut escape = String::new();
+    let digits = if letter == 'x' {
+        escape.extend(input.take(2).map(|(_, c)| c));
+        escape.clone()
+    } else {
+        for (_, c) in input {
+            escape.push(c);
+            if c == '}' || escape.len() > 8 {
+                break;
+            }
+        }
+        if escape.len() > 2 && escape.starts_with('{') && escape.ends_with('}') {
+            escape[1..escape.len() - 1].to_owned()
+        } else {
+            String::new()
+        }
+    };
+    let valid_length = if letter == 'x' { digits.len() == 2 } else { !digits.is_empty() && digits.len() <= 6 };
+    let c = if valid_length && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        u32::from_str_radix(&digits, 16).ok().and_then(::std::char::from_u32)
+    } else {
+        None
+    };
+    c.ok_or_else(|| ParseError::new(position, format!("invalid escape `\\{}{}`", letter, escape)))
+}
+
+// escapes have to be resolved front to back before the tokens are reversed:
+// in `\\*` the star is an operator, in `\*` it is not
+fn tokenize<T: Iterator<Item = char>>(input: &mut T, syntax: &SyntaxConfig) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = vec![];
+    let mut input = input.enumerate();
+    while let Some((i, c)) = input.next() {
+        if syntax.is_disabled(c) {
+            tokens.push((i, Token::Escaped(c)));
+        } else if c == '\\' {
+            match input.next() {
+                #[cfg(feature = "unicode")]
+                Some((_, c)) if c == 'p' || c == 'P' => tokens.push((i, property(&mut input, i, c == 'P')?)),
+                Some((_, c)) if c == 'x' || c == 'u' => tokens.push((i, Token::Literal(code(&mut input, i, c)?))),
+                Some((_, c)) => tokens.push((i, Token::Escaped(c))),
+                None => return Err(ParseError::new(i, "trailing `\\`")),
+            }
+        } else {
+            tokens.push((i, Token::Plain(c)));
         }
-        Some(_) => Err(ParseError(line!())),
     }
+    Ok(tokens)
 }
 
 pub fn parse<T: DoubleEndedIterator<Item = char>>(input: &mut T) -> Result<RegExpr, ParseError> {
-    branch(&mut input.rev().peekable())
+    parse_with_syntax(input, &SyntaxConfig::default())
+}
+
+pub fn parse_with_syntax<T: Iterator<Item = char>>(input: &mut T,
+                                                   syntax: &SyntaxConfig)
+                                                   -> Result<RegExpr, ParseError> {
+    let tokens = tokenize(input, syntax)?;
+    let mut expr = branch(&mut tokens.into_iter().rev().peekable())?;
+    expr.number_groups(&mut 0);
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(pattern: &str) -> RegExpr {
+        parse(&mut pattern.chars()).unwrap()
+    }
+
+    fn error(pattern: &str) -> (usize, String) {
+        let error = parse(&mut pattern.chars()).unwrap_err();
+        (error.position(), error.message().to_owned())
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("[abc"), (0, "unclosed `[`".to_owned()));
+        assert_eq!(error("*a"), (0, "dangling `*` with no preceding expression".to_owned()));
+        assert_eq!(error("a|+b"), (2, "dangling `+` with no preceding expression".to_owned()));
+        assert_eq!(error("a)"), (1, "unmatched `)`".to_owned()));
+        assert_eq!(error("ab]"), (2, "unmatched `]`".to_owned()));
+        assert_eq!(error("ab[z-a]").0, 3);
+        assert_eq!(error("ab{2,1}").0, 2);
+        assert_eq!(error("{3}"), (0, "quantifier without operand".to_owned()));
+        assert_eq!(error("a|{3}"), (2, "quantifier without operand".to_owned()));
+        assert_eq!(error(r"ab\").0, 2);
+        assert_eq!(parse(&mut "[abc".chars()).unwrap_err().to_string(), "unclosed `[` at position 0");
+    }
+
+    #[test]
+    fn postfix_operators() {
+        assert_eq!(expr("a*"), RegExpr::Repeation(Box::new(RegExpr::Character('a'))));
+        assert_eq!(expr("a+"), RegExpr::Plus(Box::new(RegExpr::Character('a'))));
+        assert_eq!(expr("ab+"),
+                   RegExpr::Sequence(vec![RegExpr::Character('a'), RegExpr::Plus(Box::new(RegExpr::Character('b')))]));
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(expr("a."), RegExpr::Sequence(vec![RegExpr::Character('a'), RegExpr::Any]));
+    }
+
+    #[test]
+    fn bounded_repetition() {
+        assert_eq!(expr("a{2,}"),
+                   RegExpr::Repeat {
+                       expr: Box::new(RegExpr::Character('a')),
+                       min: 2,
+                       max: None,
+                   });
+        for pattern in &["a{}", "a{2", "a{x}", "a{,2}"] {
+            assert!(parse(&mut pattern.chars()).is_err(), "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn anchors() {
+        assert_eq!(expr("^.$"),
+                   RegExpr::Sequence(vec![RegExpr::StartAnchor, RegExpr::Any, RegExpr::EndAnchor]));
+    }
+
+    #[test]
+    fn optional() {
+        assert_eq!(expr("a?"),
+                   RegExpr::Repeat {
+                       expr: Box::new(RegExpr::Character('a')),
+                       min: 0,
+                       max: Some(1),
+                   });
+    }
+
+    #[test]
+    fn groups_are_numbered_left_to_right() {
+        let group = |expr: RegExpr, number: usize| RegExpr::Group(Box::new(expr), number);
+        assert_eq!(expr("((a)b)(c)"),
+                   RegExpr::Sequence(vec![group(RegExpr::Sequence(vec![group(RegExpr::Character('a'), 2),
+                                                                       RegExpr::Character('b')]),
+                                                1),
+                                          group(RegExpr::Character('c'), 3)]));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(expr(r"\*\\"), RegExpr::Sequence(vec![RegExpr::Character('*'), RegExpr::Character('\\')]));
+        assert_eq!(expr(r"[\]a]"), RegExpr::Range(vec![']', 'a']));
+    }
+
+    #[test]
+    fn perl_classes() {
+        assert_eq!(expr(r"\d"), RegExpr::Range(('0'..='9').collect()));
+        assert_eq!(expr(r"\S"), RegExpr::NegatedRange(vec!['\t', '\n', '\u{b}', '\u{c}', '\r', ' ']));
+        assert!(parse(&mut r"[\D]".chars()).is_err());
+    }
+
+    #[test]
+    fn code_escapes() {
+        assert_eq!(expr(r"\x41\u{1F600}"),
+                   RegExpr::Sequence(vec![RegExpr::Character('A'), RegExpr::Character('\u{1f600}')]));
+        assert!(parse(&mut r"\x4".chars()).is_err());
+    }
+
+    #[test]
+    fn class_ranges() {
+        assert_eq!(expr("[a-c-]"), RegExpr::Range(vec!['a', 'b', 'c', '-']));
+    }
+
+    #[test]
+    fn negated_classes() {
+        assert_eq!(expr("[^a]"), RegExpr::NegatedRange(vec!['a']));
+    }
+
+    #[test]
+    fn posix_classes() {
+        assert_eq!(expr("[[:digit:]x]"), RegExpr::Range(('0'..='9').chain(Some('x')).collect()));
+        assert!(parse(&mut "[[:digits:]]".chars()).is_err());
+    }
+
+    #[test]
+    fn colons_without_posix_class() {
+        let alpha: Vec<char> = ('A'..='Z').chain('a'..='z').collect();
+        assert_eq!(expr("[[:alpha:]]"), RegExpr::Range(alpha));
+        assert_eq!(expr("[:]"), RegExpr::Range(vec![':']));
+        assert_eq!(expr("a[:]"), RegExpr::Sequence(vec![RegExpr::Character('a'), RegExpr::Range(vec![':'])]));
+        assert_eq!(expr("[a:]"), RegExpr::Range(vec!['a', ':']));
+    }
+
+    #[test]
+    fn syntax_config() {
+        let syntax = SyntaxConfig::new().disable('*');
+        assert_eq!(parse_with_syntax(&mut "a*".chars(), &syntax).unwrap(),
+                   RegExpr::Sequence(vec![RegExpr::Character('a'), RegExpr::Character('*')]));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(expr("k").case_insensitive(), RegExpr::Range(vec!['K', 'k', '\u{212a}']));
+        assert_eq!(expr("1").case_insensitive(), RegExpr::Character('1'));
+    }
+
+    #[test]
+    fn alternatives() {
+        assert_eq!(expr("a|b|cd").alternatives(), vec![&expr("a"), &expr("b"), &expr("cd")]);
+        assert_eq!(expr("(ab|a.)").alternatives(), vec![&expr("ab"), &expr("a.")]);
+        assert_eq!(expr("(a|b)|c").alternatives(), vec![&expr("(a|b)"), &expr("c")]);
+        assert_eq!(expr("(a|b)c").alternatives().len(), 1);
+    }
+
+    #[test]
+    fn canonical_key() {
+        assert_eq!(expr("(ab)*").canonical_key(), expr("(ab)*").canonical_key());
+        assert!(expr("(ab)*").canonical_key() != expr("(ba)*").canonical_key());
+        assert!(expr("a{2}").canonical_key() != expr("a{2,}").canonical_key());
+        assert!(expr("[ab]").canonical_key() != expr("[^ab]").canonical_key());
+        // fixed, whatever the toolchain
+        assert_eq!(expr("a").canonical_key(), 0x456c22c6083e9a9e);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn properties_as_ranges() {
+        let upper = match expr(r"\p{Lu}") {
+            RegExpr::Ranges(ranges) => ranges,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(upper[0], ('A', 'Z'));
+        assert_eq!(expr(r"\P{Lu}"), RegExpr::Ranges(ranges::complement(&upper)));
+        assert_eq!(expr(r"[^\p{Lu}]"), expr(r"\P{Lu}"));
+        match expr(r"[\p{Lu}a-c]") {
+            RegExpr::Ranges(ranges) => assert_eq!(ranges[..2], [('A', 'Z'), ('a', 'c')]),
+            other => panic!("{:?}", other),
+        }
+        match expr(r"\p{Lu}").case_insensitive() {
+            RegExpr::Ranges(ranges) => assert!(ranges::contains(&ranges, 'k') && ranges::contains(&ranges, '\u{212a}')),
+            other => panic!("{:?}", other),
+        }
+        assert!(expr(r"\p{Lu}").canonical_key() != expr(r"\P{Lu}").canonical_key());
+    }
 }
@@ -0,0 +1,52 @@
+// the matchers step through their input a char at a time, but report byte
+// offsets into it. a PositionMap translates between the two: step i is the
+// byte offset of the i-th char, and the step after the last char is the end
+// of the string.
+
+pub struct PositionMap {
+    // one offset per step, so one more than there are chars
+    offsets: Vec<usize>,
+}
+
+impl PositionMap {
+    pub fn new(s: &str) -> PositionMap {
+        PositionMap { offsets: s.char_indices().map(|(i, _)| i).chain(Some(s.len())).collect() }
+    }
+
+    // the step at the end of the string
+    pub fn last_step(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn byte(&self, step: usize) -> usize {
+        self.offsets[step]
+    }
+
+    // byte has to be at a char boundary
+    pub fn step(&self, byte: usize) -> usize {
+        self.offsets.binary_search(&byte).expect("offset at a char boundary")
+    }
+
+    pub fn bytes(&self, (start, end): (usize, usize)) -> (usize, usize) {
+        (self.byte(start), self.byte(end))
+    }
+
+    pub fn steps(&self, (start, end): (usize, usize)) -> (usize, usize) {
+        (self.step(start), self.step(end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_byte_chars() {
+        let positions = PositionMap::new("aé😀b");
+        assert_eq!(positions.last_step(), 4);
+        assert_eq!((0..5).map(|step| positions.byte(step)).collect::<Vec<_>>(), vec![0, 1, 3, 7, 8]);
+        assert_eq!(positions.steps((1, 7)), (1, 3));
+        assert_eq!(positions.bytes((2, 4)), (3, 8));
+        assert_eq!(PositionMap::new("").last_step(), 0);
+    }
+}
@@ -0,0 +1,222 @@
+// sets of chars as sorted, disjoint lists of inclusive ranges, for classes
+// too large to list char by char, like those of `\p{..}`. the surrogates are
+// not chars, so no range starts or ends inside them.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+const SURROGATES: (u32, u32) = (0xd800, 0xdfff);
+
+// the same chars as ranges, sorted and disjoint. ranges that overlap or touch
+// are merged, also across the surrogates.
+pub fn normalize(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort();
+    let mut ret: Vec<(char, char)> = vec![];
+    for (first, last) in ranges {
+        if let Some(&mut (_, ref mut end)) = ret.last_mut() {
+            if next(*end).is_none_or(|next| first <= next) {
+                *end = cmp::max(*end, last);
+                continue;
+            }
+        }
+        ret.push((first, last));
+    }
+    ret
+}
+
+// the chars in none of ranges, which are sorted and disjoint
+pub fn complement(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut ret = vec![];
+    // the first char no range covered so far, None past the last char
+    let mut uncovered = Some('\0');
+    for &(first, last) in ranges {
+        if let Some(uncovered) = uncovered.filter(|&uncovered| uncovered < first) {
+            ret.push((uncovered, previous(first)));
+        }
+        uncovered = next(last);
+    }
+    ret.extend(uncovered.map(|uncovered| (uncovered, char::MAX)));
+    ret
+}
+
+pub fn contains(ranges: &[(char, char)], c: char) -> bool {
+    // the last range starting at or before c is the only one that can hold it
+    match ranges.binary_search_by(|&(first, _)| first.cmp(&c)) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(i) => c <= ranges[i - 1].1,
+    }
+}
+
+// the stretches of chars that at least one of ranges covers, cut wherever any
+// of them starts or ends and around the chars in points, which are left out.
+// ranges may overlap, and all chars of a stretch are in the same ones of them.
+pub fn split(ranges: &[(char, char)], points: &BTreeSet<char>) -> Vec<(char, char)> {
+    // how many ranges start at each char, minus how many ended before it
+    let mut starts: BTreeMap<char, i64> = BTreeMap::new();
+    for &(first, last) in ranges {
+        *starts.entry(first).or_insert(0) += 1;
+        if let Some(after) = next(last) {
+            *starts.entry(after).or_insert(0) -= 1;
+        }
+    }
+    let mut ret = vec![];
+    let mut covering = 0;
+    let mut bounds = starts.iter().peekable();
+    while let Some((&first, &count)) = bounds.next() {
+        covering += count;
+        if covering == 0 {
+            continue;
+        }
+        let last = bounds.peek().map_or(char::MAX, |&(&bound, _)| previous(bound));
+        let mut uncut = Some(first);
+        for &point in points.range(first..=last) {
+            if let Some(uncut) = uncut.filter(|&uncut| uncut < point) {
+                ret.push((uncut, previous(point)));
+            }
+            uncut = next(point);
+        }
+        ret.extend(uncut.filter(|&uncut| uncut <= last).map(|uncut| (uncut, last)));
+    }
+    ret
+}
+
+// number of chars from first to last
+pub fn len(first: char, last: char) -> u64 {
+    let len = (last as u32 - first as u32 + 1) as u64;
+    if (first as u32) < SURROGATES.0 && (last as u32) > SURROGATES.1 {
+        len - (SURROGATES.1 - SURROGATES.0 + 1) as u64
+    } else {
+        len
+    }
+}
+
+// the char after c, skipping the surrogates, if c is not the last one
+pub fn next(c: char) -> Option<char> {
+    match c as u32 + 1 {
+        0xd800 => Some('\u{e000}'),
+        next => char::from_u32(next),
+    }
+}
+
+// the char before c, which is not the first one, skipping the surrogates
+pub fn previous(c: char) -> char {
+    match c as u32 - 1 {
+        0xdfff => '\u{d7ff}',
+        previous => char::from_u32(previous).unwrap(),
+    }
+}
+
+// ranges of bytes, one for each byte of some UTF-8 encoded chars
+pub type Utf8Sequence = Vec<(u8, u8)>;
+
+// the UTF-8 encodings of the chars from first to last, as sequences of byte
+// ranges: some bytes encode one of those chars exactly when they are as many
+// as the ranges of a sequence and each is in the range at its position.
+pub fn utf8_sequences(first: char, last: char) -> Vec<Utf8Sequence> {
+    let mut ret = vec![];
+    let mut pending = vec![(first as u32, last as u32)];
+    'pending: while let Some((start, end)) = pending.pop() {
+        if start <= SURROGATES.1 && SURROGATES.0 <= end {
+            if start < SURROGATES.0 {
+                pending.push((start, SURROGATES.0 - 1));
+            }
+            if SURROGATES.1 < end {
+                pending.push((SURROGATES.1 + 1, end));
+            }
+            continue;
+        }
+        // the last chars encoded in 1, 2 and 3 bytes
+        for &longest in &[0x7f, 0x7ff, 0xffff] {
+            if start <= longest && longest < end {
+                pending.push((start, longest));
+                pending.push((longest + 1, end));
+                continue 'pending;
+            }
+        }
+        // where start and end first differ in a byte, the bytes after it have
+        // to run from the lowest continuation byte to the highest
+        for i in 1..4 {
+            let mask = (1 << (6 * i)) - 1;
+            if start & !mask != end & !mask {
+                if start & mask != 0 {
+                    pending.push((start, start | mask));
+                    pending.push(((start | mask) + 1, end));
+                    continue 'pending;
+                }
+                if end & mask != mask {
+                    pending.push((start, (end & !mask) - 1));
+                    pending.push((end & !mask, end));
+                    continue 'pending;
+                }
+            }
+        }
+        let (mut start_bytes, mut end_bytes) = ([0; 4], [0; 4]);
+        let start_bytes = char::from_u32(start).unwrap().encode_utf8(&mut start_bytes).as_bytes();
+        let end_bytes = char::from_u32(end).unwrap().encode_utf8(&mut end_bytes).as_bytes();
+        ret.push(start_bytes.iter().cloned().zip(end_bytes.iter().cloned()).collect());
+    }
+    ret.sort();
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_merges_touching_ranges() {
+        assert_eq!(normalize(vec![('x', 'z'), ('a', 'c'), ('b', 'f'), ('g', 'g')]), vec![('a', 'g'), ('x', 'z')]);
+        assert_eq!(normalize(vec![('\u{e000}', '\u{e000}'), ('\u{d7ff}', '\u{d7ff}')]), vec![('\u{d7ff}', '\u{e000}')]);
+        assert_eq!(normalize(vec![('a', char::MAX), ('b', 'c')]), vec![('a', char::MAX)]);
+    }
+
+    #[test]
+    fn complement_skips_surrogates() {
+        assert_eq!(complement(&[]), vec![('\0', char::MAX)]);
+        assert_eq!(complement(&[('\0', '\u{d7ff}')]), vec![('\u{e000}', char::MAX)]);
+        assert_eq!(complement(&[('\u{e000}', char::MAX)]), vec![('\0', '\u{d7ff}')]);
+        assert_eq!(complement(&[('\0', 'a'), ('c', char::MAX)]), vec![('b', 'b')]);
+        assert_eq!(complement(&complement(&[('a', 'z'), ('\u{e000}', '\u{e001}')])),
+                   vec![('a', 'z'), ('\u{e000}', '\u{e001}')]);
+    }
+
+    #[test]
+    fn split_at_every_end() {
+        let points = ['c', 'z'].iter().cloned().collect();
+        assert_eq!(split(&[('a', 'f'), ('d', 'h'), ('x', 'z')], &points),
+                   vec![('a', 'b'), ('d', 'f'), ('g', 'h'), ('x', 'y')]);
+        assert_eq!(split(&[('a', 'a'), ('\u{d7ff}', char::MAX)], &BTreeSet::new()),
+                   vec![('a', 'a'), ('\u{d7ff}', char::MAX)]);
+        assert_eq!(split(&[], &points), vec![]);
+    }
+
+    #[test]
+    fn contains_and_len() {
+        let ranges = [('b', 'd'), ('x', 'x')];
+        assert_eq!("abdexy".chars().map(|c| contains(&ranges, c)).collect::<Vec<_>>(),
+                   vec![false, true, true, false, true, false]);
+        assert_eq!(len('a', 'z'), 26);
+        assert_eq!(len('\0', char::MAX), 0x110000 - 0x800);
+        assert_eq!(len('\u{d7ff}', '\u{e000}'), 2);
+        assert_eq!(next('\u{d7ff}'), Some('\u{e000}'));
+        assert_eq!(next(char::MAX), None);
+    }
+
+    #[test]
+    fn utf8_sequences_of_all_chars() {
+        assert_eq!(utf8_sequences('a', 'z'), vec![vec![(b'a', b'z')]]);
+        assert_eq!(utf8_sequences('\0', char::MAX),
+                   vec![vec![(0x00, 0x7f)],
+                        vec![(0xc2, 0xdf), (0x80, 0xbf)],
+                        vec![(0xe0, 0xe0), (0xa0, 0xbf), (0x80, 0xbf)],
+                        vec![(0xe1, 0xec), (0x80, 0xbf), (0x80, 0xbf)],
+                        vec![(0xed, 0xed), (0x80, 0x9f), (0x80, 0xbf)],
+                        vec![(0xee, 0xef), (0x80, 0xbf), (0x80, 0xbf)],
+                        vec![(0xf0, 0xf0), (0x90, 0xbf), (0x80, 0xbf), (0x80, 0xbf)],
+                        vec![(0xf1, 0xf3), (0x80, 0xbf), (0x80, 0xbf), (0x80, 0xbf)],
+                        vec![(0xf4, 0xf4), (0x80, 0x8f), (0x80, 0xbf), (0x80, 0xbf)]]);
+        assert_eq!(utf8_sequences('é', 'é'), vec![vec![(0xc3, 0xc3), (0xa9, 0xa9)]]);
+    }
+}
@@ -0,0 +1,1252 @@
+use automaton::NodeAllocator;
+use automaton::DFA;
+use automaton::build_nfa;
+use automaton::build_dfa;
+use automaton::build_dfa_with_metrics;
+use automaton::DFAMetrics;
+use automaton::Graph;
+use engine::Engine;
+use engine::NfaEngine;
+use engine::TextSource;
+use parser::ParseError;
+use parser::RegExpr;
+use parser::SyntaxConfig;
+use parser::parse_with_syntax;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::iter;
+use std::slice;
+use std::time::Duration;
+use std::time::Instant;
+#[cfg(feature = "unicode-width")]
+use unicode_width::UnicodeWidthStr;
+
+// compile_with_warnings flags automata with more states than this
+const LARGE_STATE_COUNT: usize = 1000;
+
+fn compile(pattern: &str, syntax: &SyntaxConfig) -> Result<DFA, ParseError> {
+    Ok(build(&parse_with_syntax(&mut pattern.chars(), syntax)?))
+}
+
+fn build(expr: &RegExpr) -> DFA {
+    let mut alloc = NodeAllocator::new();
+    build_dfa(&build_nfa(expr, &mut alloc))
+}
+
+// a compiled pattern, hiding the parser and the automata behind it
+pub struct Regex {
+    dfa: DFA,
+    engine: Engine,
+    // only kept when the pattern has capture groups
+    groups: Option<NfaEngine>,
+    // the NFA behind the DFA, kept in tests so that test_util can check both
+    // agree whether or not there are groups
+    #[cfg(test)]
+    nfa: Option<NfaEngine>,
+    // what is_match runs instead of the DFA, for Strategy::NfaSim
+    simulation: Option<NfaEngine>,
+    // 0 when the DFA was not built from an NFA, like for from_literals
+    nfa_states: usize,
+    // None when there was no pattern to parse, like for from_literals
+    pattern: Option<String>,
+    // what the pattern parsed to, None like pattern
+    expr: Option<RegExpr>,
+}
+
+// a summary of the automata instead of all of their states
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let states = self.dfa.states();
+        let mut debug = f.debug_struct("Regex");
+        if let Some(ref pattern) = self.pattern {
+            debug.field("pattern", pattern);
+        }
+        debug.field("nfa_states", &self.nfa_states)
+             .field("dfa_states", &states.len())
+             .field("accepting_states", &states.iter().filter(|state| state.is_acceptor).count())
+             .finish()
+    }
+}
+
+// a compiled pattern over bytes, see Regex::new_bytes
+pub struct BytesRegex {
+    engine: Engine,
+}
+
+impl BytesRegex {
+    // whether all of bytes match the pattern
+    pub fn is_match(&self, bytes: &[u8]) -> bool {
+        self.engine.match_source(ByteChars(bytes))
+    }
+}
+
+// bytes as the chars from U+0000 to U+00FF that stand for them in the
+// automata of a BytesRegex
+struct ByteChars<'b>(&'b [u8]);
+
+impl<'b> TextSource for ByteChars<'b> {
+    type Chars = iter::Map<iter::Enumerate<slice::Iter<'b, u8>>, fn((usize, &u8)) -> (usize, char)>;
+
+    fn char_indices(self) -> Self::Chars {
+        fn byte_char((i, &byte): (usize, &u8)) -> (usize, char) {
+            (i, byte as char)
+        }
+        self.0.iter().enumerate().map(byte_char)
+    }
+}
+
+// where a pattern matched in a haystack
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct Match<'h> {
+    haystack: &'h str,
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    fn new(haystack: &'h str, (start, end): (usize, usize)) -> Match<'h> {
+        Match { haystack, start, end }
+    }
+
+    // byte offset of the first char of the match
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    // byte offset just past the match
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn as_str(&self) -> &'h str {
+        &self.haystack[self.start..self.end]
+    }
+
+    // the columns the match takes up in a terminal, where wide chars like
+    // most CJK ones take two, to line up highlighting under it
+    #[cfg(feature = "unicode-width")]
+    pub fn display_width(&self) -> usize {
+        UnicodeWidthStr::width(self.as_str())
+    }
+}
+
+// what the groups captured in a match, by group number with the whole match
+// at 0, see Regex::find_captures
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Captures<'h> {
+    haystack: &'h str,
+    spans: Vec<Option<(usize, usize)>>,
+}
+
+impl<'h> Captures<'h> {
+    // None for a group that took no part in the match, or that the pattern
+    // does not have. group 0 is always there, even when it is empty.
+    pub fn get(&self, group: usize) -> Option<Match<'h>> {
+        self.spans.get(group).and_then(|&span| span.map(|span| Match::new(self.haystack, span)))
+    }
+
+    // every group in order, starting with group 0
+    pub fn iter<'c>(&'c self) -> impl Iterator<Item = Option<Match<'h>>> + 'c {
+        (0..self.spans.len()).map(move |group| self.get(group))
+    }
+}
+
+// sizes of the automata behind a Regex, for monitoring
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct RegexMetrics {
+    pub nfa_states: usize,
+    pub dfa_states: usize,
+    pub dfa_transitions: usize,
+    pub alphabet_size: usize,
+    pub is_infinite: bool,
+}
+
+impl RegexMetrics {
+    // one `name value` line per metric, as Prometheus scrapes them
+    pub fn render(&self) -> String {
+        let metrics = [("regex_nfa_states", self.nfa_states),
+                       ("regex_dfa_states", self.dfa_states),
+                       ("regex_dfa_transitions", self.dfa_transitions),
+                       ("regex_alphabet_size", self.alphabet_size),
+                       ("regex_is_infinite", self.is_infinite as usize)];
+        metrics.iter().map(|&(name, value)| format!("{} {}\n", name, value)).collect()
+    }
+}
+
+// how long compiling a pattern took, see Regex::compile_with_metrics
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct CompileMetrics {
+    pub nfa_build_time: Duration,
+    pub dfa_build_time: Duration,
+    // what the subset construction went through
+    pub dfa: DFAMetrics,
+}
+
+// how the languages of two patterns differ: the shortest string matched by
+// only one of them, in each direction. both are None for equal languages.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct LanguageDiff {
+    pub only_in_self: Option<String>,
+    pub only_in_other: Option<String>,
+}
+
+impl LanguageDiff {
+    pub fn is_equal(&self) -> bool {
+        self.only_in_self.is_none() && self.only_in_other.is_none()
+    }
+}
+
+// why Regex::load_rules failed, with the line at fault counting from 1
+#[derive(Debug)]
+pub enum RuleError {
+    Io(io::Error),
+    // a line that is neither blank, a comment nor `name = pattern`
+    Malformed(usize),
+    Pattern(usize, ParseError),
+}
+
+impl RuleError {
+    // None for Io, which is not about any line
+    pub fn line(&self) -> Option<usize> {
+        match *self {
+            RuleError::Io(_) => None,
+            RuleError::Malformed(line) | RuleError::Pattern(line, _) => Some(line),
+        }
+    }
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuleError::Io(ref error) => write!(f, "{}", error),
+            RuleError::Malformed(line) => write!(f, "line {}: expected `name = pattern`", line),
+            RuleError::Pattern(line, ref error) => write!(f, "line {}: {}", line, error),
+        }
+    }
+}
+
+impl Error for RuleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            RuleError::Io(ref error) => Some(error),
+            RuleError::Malformed(_) => None,
+            RuleError::Pattern(_, ref error) => Some(error),
+        }
+    }
+}
+
+// why Regex::new_forbid_empty failed
+#[derive(Debug)]
+pub enum ForbidEmptyError {
+    Parse(ParseError),
+    // the pattern parses, but matches the empty string
+    MatchesEmpty,
+}
+
+impl fmt::Display for ForbidEmptyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ForbidEmptyError::Parse(ref error) => write!(f, "{}", error),
+            ForbidEmptyError::MatchesEmpty => write!(f, "{}", Warning::MatchesEmpty),
+        }
+    }
+}
+
+impl Error for ForbidEmptyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ForbidEmptyError::Parse(ref error) => Some(error),
+            ForbidEmptyError::MatchesEmpty => None,
+        }
+    }
+}
+
+// the engine Regex::is_match runs, see Regex::compile_with_strategy. there
+// is no lazy DFA or backtracker yet to choose from.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum Strategy {
+    // the DFA, the fastest to match with once it is built
+    Auto,
+    Dfa,
+    // simulates the NFA, see NfaEngine
+    NfaSim,
+}
+
+// something suspicious about a pattern that still compiles fine
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum Warning {
+    MatchesEmpty,
+    MatchesEverything,
+    // an alternative of `|` only matches strings the other side matches too
+    RedundantAlternative,
+    // the DFA has this many states
+    LargeAutomaton(usize),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::MatchesEmpty => write!(f, "pattern matches the empty string"),
+            Warning::MatchesEverything => write!(f, "pattern matches every string"),
+            Warning::RedundantAlternative => write!(f, "alternative is covered by another one"),
+            Warning::LargeAutomaton(states) => write!(f, "pattern compiles to {} states", states),
+        }
+    }
+}
+
+// one RedundantAlternative for every `|` with a side whose language is
+// contained in the other's
+fn redundant_alternatives(expr: &RegExpr, warnings: &mut Vec<Warning>) {
+    match *expr {
+        RegExpr::Branch(ref lhs, ref rhs) => {
+            redundant_alternatives(lhs, warnings);
+            redundant_alternatives(rhs, warnings);
+            let (lhs, rhs) = (build(lhs), build(rhs));
+            if lhs.difference_witness(&rhs).is_none() || rhs.difference_witness(&lhs).is_none() {
+                warnings.push(Warning::RedundantAlternative);
+            }
+        }
+        RegExpr::Repeation(ref expr) |
+        RegExpr::Plus(ref expr) |
+        RegExpr::Repeat { ref expr, .. } |
+        RegExpr::Group(ref expr, _) => redundant_alternatives(expr, warnings),
+        RegExpr::Sequence(ref v) => {
+            for expr in v {
+                redundant_alternatives(expr, warnings);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, ParseError> {
+        Regex::compile_with_syntax(pattern, SyntaxConfig::default())
+    }
+
+    // like new, but also reports what looks like a mistake in the pattern.
+    // warnings never keep the pattern from compiling.
+    pub fn compile_with_warnings(pattern: &str) -> Result<(Regex, Vec<Warning>), ParseError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default())?;
+        let regex = Regex::from_expr(&expr).with_pattern(pattern);
+
+        let mut warnings = vec![];
+        if regex.is_match("") {
+            warnings.push(Warning::MatchesEmpty);
+        }
+        if build(&RegExpr::Repeation(Box::new(RegExpr::Any))).difference_witness(&regex.dfa).is_none() {
+            warnings.push(Warning::MatchesEverything);
+        }
+        redundant_alternatives(&expr, &mut warnings);
+        let states = regex.dfa.states().len();
+        if states > LARGE_STATE_COUNT {
+            warnings.push(Warning::LargeAutomaton(states));
+        }
+        Ok((regex, warnings))
+    }
+
+    // like new, but also reports how long building the automata took
+    pub fn compile_with_metrics(pattern: &str) -> Result<(Regex, CompileMetrics), ParseError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default())?;
+        let started = Instant::now();
+        let nfa = build_nfa(&expr, &mut NodeAllocator::new());
+        let nfa_build_time = started.elapsed();
+        let started = Instant::now();
+        let (dfa, dfa_metrics) = build_dfa_with_metrics(&nfa);
+        let metrics = CompileMetrics {
+            nfa_build_time,
+            dfa_build_time: started.elapsed(),
+            dfa: dfa_metrics,
+        };
+        Ok((Regex::from_automata(&expr, nfa, dfa).with_pattern(pattern), metrics))
+    }
+
+    // like new, but only the metacharacters enabled in syntax are operators
+    pub fn compile_with_syntax(pattern: &str, syntax: SyntaxConfig) -> Result<Regex, ParseError> {
+        Ok(Regex::from_expr(&parse_with_syntax(&mut pattern.chars(), &syntax)?).with_pattern(pattern))
+    }
+
+    // like new, but is_match runs the engine strategy picks. the DFA is
+    // built either way, since find, captures and the rest run on it.
+    pub fn compile_with_strategy(pattern: &str, strategy: Strategy) -> Result<Regex, ParseError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default())?;
+        let nfa = build_nfa(&expr, &mut NodeAllocator::new());
+        let dfa = build_dfa(&nfa);
+        let simulation = match strategy {
+            Strategy::NfaSim => Some(NfaEngine::new(nfa.clone())),
+            Strategy::Auto | Strategy::Dfa => None,
+        };
+        let mut regex = Regex::from_automata(&expr, nfa, dfa).with_pattern(pattern);
+        regex.simulation = simulation;
+        Ok(regex)
+    }
+
+    // the pattern over bytes rather than chars, for binary protocols, see
+    // RegExpr::to_bytes
+    pub fn new_bytes(pattern: &str) -> Result<BytesRegex, ParseError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default())?;
+        Ok(BytesRegex { engine: Engine::new(build(&expr.to_bytes())) })
+    }
+
+    // like new, but `.` and negated classes only match chars in universe,
+    // like DNA over ACGT, see RegExpr::within
+    pub fn compile_with_universe(pattern: &str, universe: &BTreeSet<char>) -> Result<Regex, ParseError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default())?;
+        Ok(Regex::from_expr(&expr.within(universe)).with_pattern(pattern))
+    }
+
+    // like new, but every char in the pattern matches regardless of case
+    pub fn new_case_insensitive(pattern: &str) -> Result<Regex, ParseError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default())?;
+        Ok(Regex::from_expr(&expr.case_insensitive()).with_pattern(pattern))
+    }
+
+    // like new, but a pattern that matches the empty string is an error too,
+    // for lexers where an empty token would be a bug
+    pub fn new_forbid_empty(pattern: &str) -> Result<Regex, ForbidEmptyError> {
+        let expr = parse_with_syntax(&mut pattern.chars(), &SyntaxConfig::default()).map_err(ForbidEmptyError::Parse)?;
+        let nfa = build_nfa(&expr, &mut NodeAllocator::new());
+        if nfa.accepts_empty() {
+            return Err(ForbidEmptyError::MatchesEmpty);
+        }
+        let dfa = build_dfa(&nfa);
+        Ok(Regex::from_automata(&expr, nfa, dfa).with_pattern(pattern))
+    }
+
+    // matches exactly the given strings, as a whole, like their alternation
+    // would but without parsing them: the DFA is built as a trie, see
+    // DFA::from_literals. there is no NFA behind it.
+    pub fn from_literals<S: AsRef<str>>(literals: &[S]) -> Regex {
+        Regex::from_dfa(DFA::from_literals(literals))
+    }
+
+    // compiles the rules of a rules file, one `name = pattern` per line, in
+    // order. blank lines and lines starting with `#` are skipped, and the
+    // whitespace around the name and the pattern is not part of them.
+    pub fn load_rules<R: BufRead>(reader: R) -> Result<Vec<(String, Regex)>, RuleError> {
+        let mut rules = vec![];
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(RuleError::Io)?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, pattern) = match line.find('=') {
+                Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+                None => return Err(RuleError::Malformed(i + 1)),
+            };
+            if name.is_empty() {
+                return Err(RuleError::Malformed(i + 1));
+            }
+            let regex = Regex::new(pattern).map_err(|error| RuleError::Pattern(i + 1, error))?;
+            rules.push((name.to_owned(), regex));
+        }
+        Ok(rules)
+    }
+
+    fn from_dfa(dfa: DFA) -> Regex {
+        Regex {
+            engine: Engine::new(dfa.clone()),
+            dfa,
+            groups: None,
+            #[cfg(test)]
+            nfa: None,
+            simulation: None,
+            nfa_states: 0,
+            pattern: None,
+            expr: None,
+        }
+    }
+
+    fn with_pattern(mut self, pattern: &str) -> Regex {
+        self.pattern = Some(pattern.to_owned());
+        self
+    }
+
+    fn from_expr(expr: &RegExpr) -> Regex {
+        let mut alloc = NodeAllocator::new();
+        let nfa = build_nfa(expr, &mut alloc);
+        let dfa = build_dfa(&nfa);
+        Regex::from_automata(expr, nfa, dfa)
+    }
+
+    // nfa has to be built from expr, and dfa from nfa
+    fn from_automata(expr: &RegExpr, nfa: Graph, dfa: DFA) -> Regex {
+        let mut regex = Regex::from_dfa(dfa);
+        regex.expr = Some(expr.clone());
+        regex.nfa_states = nfa.node_count();
+        #[cfg(test)]
+        {
+            regex.nfa = Some(NfaEngine::new(nfa.clone()));
+        }
+        if nfa.group_count() > 0 {
+            regex.groups = Some(NfaEngine::new(nfa));
+        }
+        regex
+    }
+
+    // whether the whole of s matches the pattern
+    pub fn is_match<S: AsRef<str>>(&self, s: S) -> bool {
+        match self.simulation {
+            Some(ref nfa) => nfa.match_string(s),
+            None => self.engine.match_string(s),
+        }
+    }
+
+    // the simulation of the NFA behind the DFA, None when there was none
+    #[cfg(test)]
+    pub fn nfa_engine(&self) -> Option<&NfaEngine> {
+        self.nfa.as_ref()
+    }
+
+    // the leftmost match in haystack, and the longest among those starting
+    // there, see Engine::find
+    pub fn find<'h>(&self, haystack: &'h str) -> Option<Match<'h>> {
+        self.find_span(haystack).map(|span| Match::new(haystack, span))
+    }
+
+    // like find, but only the byte offsets of the match
+    pub fn find_span(&self, haystack: &str) -> Option<(usize, usize)> {
+        self.engine.find(haystack)
+    }
+
+    // every match in haystack from left to right, see Engine::find_iter
+    pub fn find_iter<'r, 'h: 'r>(&'r self, haystack: &'h str) -> impl Iterator<Item = Match<'h>> + 'r {
+        self.engine.find_iter(haystack).map(move |span| Match::new(haystack, span))
+    }
+
+    // like find_iter, but only the matches of at least min chars, see
+    // Engine::find_iter_min_len
+    pub fn find_iter_min_len<'r, 'h: 'r>(&'r self, haystack: &'h str, min: usize) -> impl Iterator<Item = Match<'h>> + 'r {
+        self.engine.find_iter_min_len(haystack, min).map(move |span| Match::new(haystack, span))
+    }
+
+    // find_iter over each line of reader in turn, for grep-like tools that
+    // should not read all of their input up front. matches come as
+    // (line, start, end), with lines numbered from 1 and the offsets into the
+    // line without its `\n` or `\r\n`, so `$` holds right before those.
+    pub fn find_iter_lines<'r, R: BufRead + 'r>(&'r self, reader: R) -> impl Iterator<Item = io::Result<(usize, usize, usize)>> + 'r {
+        reader.lines().enumerate().flat_map(move |(i, line)| {
+            let found: Vec<io::Result<(usize, usize, usize)>> = match line {
+                Ok(line) => self.find_iter(&line).map(|found| Ok((i + 1, found.start(), found.end()))).collect(),
+                Err(error) => vec![Err(error)],
+            };
+            found
+        })
+    }
+
+    // haystack with every match replaced by replacement, taken literally.
+    // an empty replacement deletes the matches, see Engine::delete_all.
+    pub fn replace_all(&self, haystack: &str, replacement: &str) -> String {
+        self.engine.replace_all(haystack, replacement)
+    }
+
+    // haystack with every match replaced by replacement, taken literally,
+    // and the number of replacements, see Engine::replace_all
+    pub fn replace_all_count(&self, haystack: &str, replacement: &str) -> (String, usize) {
+        self.engine.replace_all_count(haystack, replacement)
+    }
+
+    // the pieces of haystack between the matches of find_iter, in order.
+    // put back together with the matches, they give haystack again, so an
+    // empty haystack still has one, empty, piece.
+    pub fn split_borrowed<'r, 'h: 'r>(&'r self, haystack: &'h str) -> impl Iterator<Item = &'h str> + 'r {
+        Pieces {
+            haystack,
+            spans: self.engine.find_iter(haystack),
+            copied: Some(0),
+            inclusive: false,
+        }
+    }
+
+    // like split_borrowed, but each match stays at the end of the piece
+    // before it, as with str::split_inclusive. there is no empty piece after
+    // a match at the end of haystack, so `a\nb\n` split on `\n` gives `a\n`
+    // and `b\n`.
+    pub fn split_inclusive<'r, 'h: 'r>(&'r self, haystack: &'h str) -> impl Iterator<Item = &'h str> + 'r {
+        Pieces {
+            haystack,
+            spans: self.engine.find_iter(haystack),
+            copied: Some(0),
+            inclusive: true,
+        }
+    }
+
+    // splits input after the longest prefix the pattern matches, as a lexer
+    // would, into (matched, rest). None if no prefix matches.
+    pub fn consume<'h>(&self, input: &'h str) -> Option<(&'h str, &'h str)> {
+        self.engine.match_prefix_remainder(input).map(|rest| input.split_at(input.len() - rest.len()))
+    }
+
+    // what the groups matched when the whole of s matches, by group number
+    // with all of s at 0. a group that took no part in the match is None,
+    // unlike one that matched the empty string.
+    pub fn captures<'t>(&self, s: &'t str) -> Option<Vec<Option<&'t str>>> {
+        match self.groups {
+            Some(ref groups) => {
+                groups.captures(s).map(|spans| {
+                    spans.into_iter().map(|span| span.map(|(start, end)| &s[start..end])).collect()
+                })
+            }
+            None if self.is_match(s) => Some(vec![Some(s)]),
+            None => None,
+        }
+    }
+
+    // the captures of the match find reports. unlike captures, the match can
+    // be anywhere in haystack, and group 0 is that match even when it is
+    // empty, as for `a*` in `b`.
+    pub fn find_captures<'h>(&self, haystack: &'h str) -> Option<Captures<'h>> {
+        self.find_span(haystack).map(|span| self.captures_of(haystack, span))
+    }
+
+    // the captures of the longest match starting right at the start of
+    // haystack, as consume finds it, for validators. unlike find_captures the
+    // match cannot start later, and unlike captures input may follow it.
+    pub fn captures_anchored<'h>(&self, haystack: &'h str) -> Option<Captures<'h>> {
+        self.engine
+            .match_prefix_remainder(haystack)
+            .map(|rest| self.captures_of(haystack, (0, haystack.len() - rest.len())))
+    }
+
+    // the captures of every match of find_iter, in order. the capture groups
+    // of all of them are worked out by a single NfaEngine::captures_spans.
+    pub fn captures_all<'h>(&self, haystack: &'h str) -> Vec<Captures<'h>> {
+        let found = self.engine.find_iter(haystack);
+        match self.groups {
+            Some(ref groups) => {
+                groups.captures_spans(haystack, found)
+                    .into_iter()
+                    .map(|spans| Captures {
+                        haystack,
+                        spans: spans.expect("the NFA accepts what the DFA found"),
+                    })
+                    .collect()
+            }
+            None => found.map(|span| Captures { haystack, spans: vec![Some(span)] }).collect(),
+        }
+    }
+
+    // captures_all as an iterator, for scanning loops. the groups are still
+    // worked out for all matches up front.
+    pub fn captures_iter<'h>(&self, haystack: &'h str) -> impl Iterator<Item = Captures<'h>> {
+        self.captures_all(haystack).into_iter()
+    }
+
+    // span has to be a match found in haystack
+    fn captures_of<'h>(&self, haystack: &'h str, span: (usize, usize)) -> Captures<'h> {
+        let spans = match self.groups {
+            Some(ref groups) => groups.captures_at(haystack, span).expect("the NFA accepts what the DFA found"),
+            None => vec![Some(span)],
+        };
+        Captures { haystack, spans }
+    }
+
+    pub fn metrics(&self) -> RegexMetrics {
+        RegexMetrics {
+            nfa_states: self.nfa_states,
+            dfa_states: self.dfa.states().len(),
+            dfa_transitions: self.dfa.edges.len(),
+            alphabet_size: self.dfa.alphabet().len(),
+            is_infinite: self.dfa.is_infinite(),
+        }
+    }
+
+    // a shortest string the pattern does not match, or None if it matches
+    // every string, see DFA::counterexample and DFA::full_alphabet
+    pub fn counterexample(&self) -> Option<String> {
+        self.dfa.counterexample(&self.dfa.full_alphabet())
+    }
+
+    // the pairs (i, j) with i < j of alternatives of the outermost `|` that
+    // match some string in common, with the alternatives numbered from 0 left
+    // to right, see RegExpr::alternatives. rules joined by `|` are ambiguous
+    // where they overlap. empty without a pattern, like for from_literals.
+    pub fn alternatives_overlap(&self) -> Vec<(usize, usize)> {
+        let alternatives: Vec<DFA> = match self.expr {
+            Some(ref expr) => expr.alternatives().into_iter().map(build).collect(),
+            None => vec![],
+        };
+        let mut overlaps = vec![];
+        for (i, lhs) in alternatives.iter().enumerate() {
+            for (j, rhs) in alternatives.iter().enumerate().skip(i + 1) {
+                if lhs.intersection_witness(rhs).is_some() {
+                    overlaps.push((i, j));
+                }
+            }
+        }
+        overlaps
+    }
+
+    pub fn diff(&self, other: &Regex) -> LanguageDiff {
+        LanguageDiff {
+            only_in_self: self.dfa.difference_witness(&other.dfa),
+            only_in_other: other.dfa.difference_witness(&self.dfa),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a str> for Regex {
+    type Error = ParseError;
+
+    fn try_from(pattern: &'a str) -> Result<Regex, ParseError> {
+        Regex::new(pattern)
+    }
+}
+
+impl TryFrom<String> for Regex {
+    type Error = ParseError;
+
+    fn try_from(pattern: String) -> Result<Regex, ParseError> {
+        Regex::new(&pattern)
+    }
+}
+
+// the pieces of haystack around spans, which are in order and do not overlap.
+// with inclusive, each piece ends with the span after it.
+struct Pieces<'h, I> {
+    haystack: &'h str,
+    spans: I,
+    // where the next piece starts, None once the last one was returned
+    copied: Option<usize>,
+    inclusive: bool,
+}
+
+impl<'h, I: Iterator<Item = (usize, usize)>> Iterator for Pieces<'h, I> {
+    type Item = &'h str;
+
+    fn next(&mut self) -> Option<&'h str> {
+        let copied = self.copied?;
+        match self.spans.next() {
+            Some((start, end)) => {
+                self.copied = Some(end);
+                Some(&self.haystack[copied..if self.inclusive { end } else { start }])
+            }
+            None => {
+                self.copied = None;
+                if self.inclusive && copied == self.haystack.len() {
+                    return None;
+                }
+                Some(&self.haystack[copied..])
+            }
+        }
+    }
+}
+
+// a set of patterns matching what any of them matches, which can grow.
+// adding a pattern only determinizes the new one and combines it with the
+// existing DFA through DFA::union, instead of rebuilding the whole set.
+pub struct MultiRegex {
+    dfa: DFA,
+    engine: Engine,
+    // an engine for each pattern on its own, in the order they were added
+    rules: Vec<Engine>,
+}
+
+impl Default for MultiRegex {
+    fn default() -> MultiRegex {
+        MultiRegex::new()
+    }
+}
+
+impl MultiRegex {
+    // the empty set, matching nothing
+    pub fn new() -> MultiRegex {
+        let dfa = DFA::from_literals::<&str>(&[]);
+        MultiRegex {
+            engine: Engine::new(dfa.clone()),
+            dfa,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn add_pattern(&mut self, pattern: &str) -> Result<(), ParseError> {
+        let added = compile(pattern, &SyntaxConfig::default())?;
+        self.dfa = DFA::union(&[&self.dfa, &added]);
+        self.engine = Engine::new(self.dfa.clone());
+        self.rules.push(Engine::new(added));
+        Ok(())
+    }
+
+    // whether the whole of s matches one of the patterns
+    pub fn is_match<S: AsRef<str>>(&self, s: S) -> bool {
+        self.engine.match_string(s)
+    }
+
+    // every match of any of the patterns from left to right, as a scanner
+    // would find its tokens, see Engine::find_iter. each one comes as
+    // (rule, start, end), where rule is the index of the first pattern added
+    // that matches it, so earlier patterns win ties like `if` as a keyword
+    // and as an identifier.
+    pub fn find_iter<'r, 'h: 'r>(&'r self, haystack: &'h str) -> impl Iterator<Item = (usize, usize, usize)> + 'r {
+        self.engine.find_iter(haystack).map(move |(start, end)| {
+            // no pattern matches past the longest match of all of them, so
+            // one that matches this far matches exactly this span
+            let rule = self.rules
+                .iter()
+                .position(|rule| rule.longest_match_at(haystack, start) == Some(end))
+                .expect("the union matches only what one of the patterns does");
+            (rule, start, end)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use test_util::assert_language;
+
+    #[test]
+    fn even_runs_of_a() {
+        let regex = Regex::new("(aa|b)*").unwrap();
+        assert_language(&regex,
+                        &["", "aa", "b", "aab", "baab", "aaaa", "bbaabb"],
+                        &["a", "ab", "aaa", "aba", "c"]);
+    }
+    #[test]
+    fn new() {
+        let regex = Regex::new("(ab)*c").unwrap();
+        assert_language(&regex, &["c", "ababc"], &["abab", "abc "]);
+        let regex = Regex::new("[a-z]+@[a-z]+").unwrap();
+        assert_language(&regex, &["foo@bar"], &["foo@", "@bar"]);
+        assert_eq!(Regex::new("a)").err().unwrap().position(), 1);
+    }
+
+    #[test]
+    fn try_from() {
+        assert_language(&Regex::try_from("a+").unwrap(), &["a", "aa"], &[""]);
+        assert_language(&Regex::try_from(String::from("(ab)*")).unwrap(), &["", "ab"], &["a"]);
+        let error: ParseError = Regex::try_from("a)").unwrap_err();
+        assert_eq!(error.position(), 1);
+        assert_eq!(Regex::try_from(String::from("(a")).unwrap_err(), Regex::new("(a").unwrap_err());
+    }
+
+    #[test]
+    fn is_match_takes_any_string() {
+        let regex = Regex::new("(ab)*").unwrap();
+        let owned = String::from("abab");
+        assert!(regex.is_match(&owned));
+        assert!(regex.is_match(owned));
+        assert!(regex.is_match(Cow::Borrowed("ab")));
+        assert!(!regex.is_match(Cow::Owned::<str>("a".to_owned())));
+        let mut multi = MultiRegex::new();
+        multi.add_pattern("x+").unwrap();
+        assert!(multi.is_match(String::from("xx")));
+        assert!(multi.is_match(Cow::Borrowed("x")));
+    }
+
+    #[test]
+    fn compile_with_syntax() {
+        let regex = Regex::compile_with_syntax("a*b", SyntaxConfig::new().disable('*')).unwrap();
+        assert_language(&regex, &["a*b"], &["aab", "b"]);
+        let regex = Regex::compile_with_syntax("a*|b", SyntaxConfig::new().disable('*')).unwrap();
+        assert_language(&regex, &["a*", "b"], &["", "a"]);
+    }
+
+    #[test]
+    fn compile_with_strategy() {
+        for pattern in &["(a|b)*c", "^a.$", "x{2,3}", "(ab)*|b"] {
+            let dfa = Regex::compile_with_strategy(pattern, Strategy::Dfa).unwrap();
+            let nfa = Regex::compile_with_strategy(pattern, Strategy::NfaSim).unwrap();
+            for input in &["", "c", "abac", "ab", "axb", "xx", "xxx", "xxxx", "abab", "b"] {
+                assert_eq!(dfa.is_match(input), nfa.is_match(input), "{:?} on {:?}", pattern, input);
+            }
+        }
+        assert!(Regex::compile_with_strategy("a)", Strategy::Auto).is_err());
+    }
+
+    #[test]
+    fn compile_with_universe() {
+        let dna: BTreeSet<char> = "ACGT".chars().collect();
+        let regex = Regex::compile_with_universe("A.[^G]", &dna).unwrap();
+        assert_language(&regex, &["AGT", "AAC"], &["AGG", "ANT", "AGN"]);
+        assert_eq!(regex.metrics().alphabet_size, 4);
+        assert!(Regex::new("A.[^G]").unwrap().is_match("ANN"));
+    }
+
+    #[test]
+    fn new_bytes() {
+        let regex = Regex::new_bytes("ab").unwrap();
+        assert!(regex.is_match(b"ab"));
+        assert!(!regex.is_match(b"abc"));
+        let regex = Regex::new_bytes("é[^a]+.").unwrap();
+        assert!(regex.is_match("ébé".as_bytes()));
+        assert!(regex.is_match(b"\xc3\xa9\x80\xff"));
+        assert!(!regex.is_match(b"\xe9b\xff"));
+        assert!(!regex.is_match("éab".as_bytes()));
+        assert!(Regex::new_bytes("[aé]").unwrap().is_match("é".as_bytes()));
+    }
+
+    #[test]
+    fn from_literals() {
+        let regex = Regex::from_literals(&["foo", "bar", "baz"]);
+        assert_language(&regex, &["foo", "baz"], &["foobar", "fo", ""]);
+        assert!(regex.diff(&Regex::new("foo|bar|baz").unwrap()).is_equal());
+        assert_eq!(regex.metrics().nfa_states, 0);
+        assert_eq!(regex.captures("bar"), Some(vec![Some("bar")]));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        assert_language(&Regex::new_case_insensitive("abc").unwrap(), &["ABC", "aBc"], &["ABD"]);
+        assert_language(&Regex::new_case_insensitive("σ").unwrap(), &["Σ", "ς"], &["s"]);
+    }
+
+    #[test]
+    fn forbid_empty() {
+        let error = Regex::new_forbid_empty("a*").unwrap_err();
+        assert_eq!(error.to_string(), "pattern matches the empty string");
+        assert_language(&Regex::new_forbid_empty("a+").unwrap(), &["a", "aa"], &[""]);
+        assert!(matches!(Regex::new_forbid_empty("a|"), Err(ForbidEmptyError::MatchesEmpty)));
+        assert!(matches!(Regex::new_forbid_empty("$^"), Err(ForbidEmptyError::MatchesEmpty)));
+        match Regex::new_forbid_empty("a)") {
+            Err(ForbidEmptyError::Parse(error)) => assert_eq!(error.position(), 1),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_alternatives() {
+        assert_language(&Regex::new("").unwrap(), &[""], &["a"]);
+        assert_language(&Regex::new("a||b").unwrap(), &["", "a", "b"], &["ab"]);
+        assert_language(&Regex::new("x(|a)*y").unwrap(), &["xy", "xaay"], &["x"]);
+    }
+
+    #[test]
+    fn warnings() {
+        let warnings = |pattern: &str| Regex::compile_with_warnings(pattern).unwrap().1;
+        assert_eq!(warnings("a+"), vec![]);
+        assert_eq!(warnings("a*"), vec![Warning::MatchesEmpty]);
+        assert_eq!(warnings(".*"), vec![Warning::MatchesEmpty, Warning::MatchesEverything]);
+        assert_eq!(warnings("a|a*b"), vec![]);
+        assert_eq!(warnings("ab|a.").len(), 1);
+        let large = warnings("(a|b)*a(a|b){9}");
+        assert!(large.iter().any(|warning| matches!(*warning, Warning::LargeAutomaton(states) if states > 1000)));
+    }
+
+    #[test]
+    fn consume() {
+        let regex = Regex::new("[a-z]+").unwrap();
+        assert_eq!(regex.consume("abc123"), Some(("abc", "123")));
+        assert_eq!(regex.consume("123"), None);
+    }
+
+    #[test]
+    fn find() {
+        let regex = Regex::new("b+").unwrap();
+        let found = regex.find("aabbb").unwrap();
+        assert_eq!((found.start(), found.end(), found.as_str()), (2, 5, "bbb"));
+        assert_eq!(regex.find_span("aabbb"), Some((2, 5)));
+        assert_eq!(regex.find("aa"), None);
+        let found = Regex::new("x*").unwrap().find("ab").unwrap();
+        assert_eq!((found.start(), found.as_str()), (0, ""));
+    }
+
+    #[test]
+    fn find_iter() {
+        let regex = Regex::new("[0-9]+").unwrap();
+        let found: Vec<(usize, &str)> = regex.find_iter("a12b3").map(|found| (found.start(), found.as_str())).collect();
+        assert_eq!(found, vec![(1, "12"), (4, "3")]);
+    }
+
+    #[test]
+    fn find_iter_min_len() {
+        let found = |pattern: &str, haystack: &str, min: usize| -> Vec<(usize, String)> {
+            let regex = Regex::new(pattern).unwrap();
+            let found = regex.find_iter_min_len(haystack, min).map(|found| (found.start(), found.as_str().to_owned()));
+            found.collect()
+        };
+        assert_eq!(found("a+", "aXaaXa", 2), vec![(2, "aa".to_owned())]);
+        assert_eq!(found("a+", "aXaaXa", 0).len(), 3);
+        assert_eq!(found("ab|bcd", "abcd", 3), vec![(1, "bcd".to_owned())]);
+        assert_eq!(found("é+", "éXéé", 2), vec![(3, "éé".to_owned())]);
+    }
+
+    #[test]
+    fn find_iter_lines() {
+        let regex = Regex::new("ba.$|fo+").unwrap();
+        let found: Vec<(usize, usize, usize)> = regex.find_iter_lines(io::Cursor::new("foo bar\r\nbaz\n\nxfoo"))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(found, vec![(1, 0, 3), (1, 4, 7), (2, 0, 3), (4, 1, 4)]);
+    }
+
+    #[test]
+    fn split_borrowed() {
+        let split = |pattern: &str, haystack: &str| -> Vec<String> {
+            Regex::new(pattern).unwrap().split_borrowed(haystack).map(str::to_owned).collect()
+        };
+        assert_eq!(split("-", "a-b-c"), vec!["a", "b", "c"]);
+        assert_eq!(split("-+", "-a--b-"), vec!["", "a", "b", ""]);
+        assert_eq!(split("-", ""), vec![""]);
+        assert_eq!(split("x*", "ab"), vec!["", "a", "b", ""]);
+
+        let regex = Regex::new("[0-9]+").unwrap();
+        let haystack = String::from("ab12cd3e");
+        let pieces: Vec<&str> = regex.split_borrowed(&haystack).collect();
+        assert_eq!(pieces, vec!["ab", "cd", "e"]);
+        let covered: usize = pieces.iter().map(|piece| piece.len()).sum::<usize>() + "123".len();
+        assert_eq!(covered, haystack.len());
+        assert_eq!(pieces[1].as_ptr(), haystack[4..].as_ptr());
+    }
+
+    #[test]
+    fn load_rules() {
+        let file = "# identifiers and numbers\n\nident = [a-z][a-z0-9]*\n  number=[0-9]+  \n";
+        let rules = Regex::load_rules(file.as_bytes()).unwrap();
+        let names: Vec<&str> = rules.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["ident", "number"]);
+        let matching = |s: &str| -> Vec<&str> {
+            rules.iter().filter(|(_, regex)| regex.is_match(s)).map(|(name, _)| name.as_str()).collect()
+        };
+        assert_eq!(matching("x1"), vec!["ident"]);
+        assert_eq!(matching("42"), vec!["number"]);
+        assert!(matching("-").is_empty());
+    }
+
+    #[test]
+    fn load_rules_reports_the_line() {
+        let error = Regex::load_rules("a = a+\n# fine so far\nb = (b\n".as_bytes()).unwrap_err();
+        assert_eq!(error.line(), Some(3));
+        assert!(matches!(error, RuleError::Pattern(3, _)));
+        assert!(error.to_string().starts_with("line 3: "));
+        assert_eq!(Regex::load_rules("a = a\nb\n".as_bytes()).unwrap_err().line(), Some(2));
+        assert_eq!(Regex::load_rules(" = a\n".as_bytes()).unwrap_err().line(), Some(1));
+    }
+
+    #[test]
+    fn split_inclusive() {
+        let split = |pattern: &str, haystack: &str| -> Vec<String> {
+            Regex::new(pattern).unwrap().split_inclusive(haystack).map(str::to_owned).collect()
+        };
+        assert_eq!(split("\n", "a\nb\n"), vec!["a\n", "b\n"]);
+        assert_eq!(split("\n", "a\nb"), vec!["a\n", "b"]);
+        assert_eq!(split("\r?\n", "a\r\n\nb"), vec!["a\r\n", "\n", "b"]);
+        assert!(split("\n", "").is_empty());
+    }
+
+    #[test]
+    fn replace_all() {
+        let regex = Regex::new("\\d").unwrap();
+        assert_eq!(regex.replace_all("a1b2c3", ""), "abc");
+        assert_eq!(regex.replace_all("a12b", ""), "ab");
+        assert_eq!(regex.replace_all("a1b", "#"), "a#b");
+        assert_eq!(Regex::new("\\d*").unwrap().replace_all("a12b", ""), "ab");
+        assert_eq!(Regex::new("\\d*").unwrap().replace_all("ab", "-"), "-a-b-");
+    }
+
+    #[test]
+    fn captures() {
+        let regex = Regex::new("(a+)(b+)?").unwrap();
+        assert_eq!(regex.captures("aab"), Some(vec![Some("aab"), Some("aa"), Some("b")]));
+        assert_eq!(regex.captures("a"), Some(vec![Some("a"), Some("a"), None]));
+        assert_eq!(Regex::new("ab").unwrap().captures("ab"), Some(vec![Some("ab")]));
+        assert_eq!(regex.captures("b"), None);
+    }
+
+    #[test]
+    fn captures_in_alternation() {
+        let regex = Regex::new("(a)|(b)").unwrap();
+        assert_eq!(regex.captures("b"), Some(vec![Some("b"), None, Some("b")]));
+        assert_eq!(regex.captures("a"), Some(vec![Some("a"), Some("a"), None]));
+    }
+
+    #[test]
+    fn find_captures() {
+        let whole = Regex::new("a*").unwrap().find_captures("b").unwrap().get(0).unwrap();
+        assert_eq!((whole.start(), whole.end()), (0, 0));
+        let whole = Regex::new("a+|").unwrap().find_captures("baa").unwrap().get(0).unwrap();
+        assert_eq!((whole.start(), whole.as_str()), (0, ""));
+
+        let regex = Regex::new("(a+)(b+)?").unwrap();
+        let captures = regex.find_captures("xxaab!").unwrap();
+        let groups: Vec<Option<&str>> = captures.iter().map(|group| group.map(|group| group.as_str())).collect();
+        assert_eq!(groups, vec![Some("aab"), Some("aa"), Some("b")]);
+        assert_eq!(captures.get(0).unwrap().start(), 2);
+        assert_eq!(captures.get(3), None);
+        assert_eq!(regex.find_captures("xyz"), None);
+
+        let empty = Regex::new("x(a*)").unwrap().find_captures("bxb").unwrap().get(1).unwrap();
+        assert_eq!((empty.start(), empty.end()), (2, 2));
+        let anchored = Regex::new("(^a)?(b)").unwrap().find_captures("ab").unwrap();
+        assert_eq!(anchored.get(1).map(|group| group.as_str()), Some("a"));
+    }
+
+    #[test]
+    fn captures_anchored() {
+        let regex = Regex::new("(\\d+)").unwrap();
+        assert!(regex.captures_anchored("x12").is_none());
+        let found = regex.find_captures("x12").unwrap();
+        assert_eq!(found.get(1).unwrap().as_str(), "12");
+        let anchored = regex.captures_anchored("12x").unwrap();
+        assert_eq!((anchored.get(0).unwrap().as_str(), anchored.get(1).unwrap().as_str()), ("12", "12"));
+        let anchored = Regex::new("(^a)?(b)").unwrap().captures_anchored("ab").unwrap();
+        assert_eq!(anchored.get(1).unwrap().as_str(), "a");
+    }
+
+    #[test]
+    fn captures_all() {
+        let regex = Regex::new("(\\w+)=(\\w+)").unwrap();
+        let pairs: Vec<(&str, &str)> = regex.captures_all("a=1 b=2")
+            .iter()
+            .map(|captures| (captures.get(1).unwrap().as_str(), captures.get(2).unwrap().as_str()))
+            .collect();
+        assert_eq!(pairs, vec![("a", "1"), ("b", "2")]);
+        assert!(regex.captures_all("a= =2").is_empty());
+
+        let starts: Vec<usize> = Regex::new("a*").unwrap().captures_all("ba").iter().map(|captures| {
+            captures.get(0).unwrap().start()
+        }).collect();
+        assert_eq!(starts, vec![0, 1, 2]);
+        let groups: Vec<Option<&str>> = Regex::new("x(y)?").unwrap().captures_all("xyx").iter().map(|captures| {
+            captures.get(1).map(|group| group.as_str())
+        }).collect();
+        assert_eq!(groups, vec![Some("y"), None]);
+    }
+
+    #[test]
+    fn captures_iter() {
+        let spans: Vec<(usize, usize)> = Regex::new("(\\w+)=\\w+")
+            .unwrap()
+            .captures_iter("ab=1 c=23")
+            .map(|captures| {
+                let key = captures.get(1).unwrap();
+                (key.start(), key.end())
+            })
+            .collect();
+        assert_eq!(spans, vec![(0, 2), (5, 6)]);
+    }
+
+    #[cfg(feature = "unicode-width")]
+    #[test]
+    fn display_width() {
+        let found = Regex::new("a.+b").unwrap().find("xa漢字bx").unwrap();
+        assert_eq!(found.display_width(), 6);
+        assert_eq!(Regex::new("c*").unwrap().find("x").unwrap().display_width(), 0);
+    }
+
+    #[test]
+    fn byte_offsets_of_multi_byte_input() {
+        let regex = Regex::new("w(ö+)r").unwrap();
+        let haystack = "héllo wöörld, wör";
+        let found = regex.find(haystack).unwrap();
+        assert_eq!((found.start(), found.end(), found.as_str()), (7, 13, "wöör"));
+        let captures = regex.find_captures(haystack).unwrap();
+        assert_eq!(captures.get(1).map(|group| (group.start(), group.end())), Some((8, 12)));
+        let spans: Vec<(usize, usize)> = regex.captures_iter(haystack)
+            .map(|captures| captures.get(1).map(|group| (group.start(), group.end())).unwrap())
+            .collect();
+        assert_eq!(spans, vec![(8, 12), (18, 20)]);
+        let long: Vec<&str> = regex.find_iter_min_len(haystack, 4).map(|found| found.as_str()).collect();
+        assert_eq!(long, vec!["wöör"]);
+    }
+
+    #[test]
+    fn captures_prefer_left_alternatives() {
+        assert_eq!(Regex::new("(a)|(a)|(a)").unwrap().captures("a"), Some(vec![Some("a"), Some("a"), None, None]));
+        // `a` is tried before `ab`, and `bcd` completes it before `abc`
+        // with the shorter `c` is ever reached
+        let captures = Regex::new("(a|ab)(c|bcd)").unwrap().find_captures("xabcdy").unwrap();
+        let groups: Vec<_> = captures.iter().map(|group| group.map(|group| group.as_str())).collect();
+        assert_eq!(groups, vec![Some("abcd"), Some("a"), Some("bcd")]);
+        let regex = Regex::new("(a|ab)(c|bcd)(d*)").unwrap();
+        assert_eq!(regex.captures("abcd"), Some(vec![Some("abcd"), Some("a"), Some("bcd"), Some("")]));
+    }
+
+    #[test]
+    fn metrics() {
+        let metrics = Regex::new("(aa|b)*").unwrap().metrics();
+        assert_eq!((metrics.dfa_states, metrics.alphabet_size, metrics.is_infinite), (4, 2, true));
+        assert!(metrics.render().contains("regex_dfa_states 4\n"));
+    }
+
+    #[test]
+    fn debug() {
+        let debug = format!("{:?}", Regex::new("(aa|b)*").unwrap());
+        assert_eq!(debug, r#"Regex { pattern: "(aa|b)*", nfa_states: 16, dfa_states: 4, accepting_states: 3 }"#);
+        let debug = format!("{:?}", Regex::from_literals(&["ab", "b"]));
+        assert_eq!(debug, "Regex { nfa_states: 0, dfa_states: 4, accepting_states: 2 }");
+    }
+
+    #[test]
+    fn compile_with_metrics() {
+        let (regex, metrics) = Regex::compile_with_metrics("(aa|b)*").unwrap();
+        assert!(metrics.dfa.iterations > 0);
+        assert_eq!(metrics.dfa.iterations, regex.metrics().dfa_states);
+        assert_eq!(metrics.dfa.transitions, regex.metrics().dfa_transitions);
+        assert!(Regex::compile_with_metrics("a)").is_err());
+    }
+
+    #[test]
+    fn counterexample() {
+        let counterexample = |pattern: &str| Regex::new(pattern).unwrap().counterexample();
+        assert_eq!(counterexample("a*"), Some("b".to_owned()));
+        assert_eq!(counterexample("(ab)*"), Some("a".to_owned()));
+        assert_eq!(counterexample("a+"), Some("".to_owned()));
+        assert_eq!(counterexample(".*"), None);
+        assert_eq!(counterexample("(a|[^a])*"), None);
+    }
+
+    #[test]
+    fn diff() {
+        let regex = |pattern: &str| Regex::new(pattern).unwrap();
+        let diff = regex("a{2,3}").diff(&regex("a{2}"));
+        assert_eq!(diff.only_in_self, Some("aaa".to_owned()));
+        assert_eq!(diff.only_in_other, None);
+        assert!(regex("^ab$").diff(&regex("ab")).is_equal());
+    }
+
+    #[test]
+    fn alternatives_overlap() {
+        assert_eq!(Regex::new("(ab|a.)").unwrap().alternatives_overlap(), vec![(0, 1)]);
+        assert_eq!(Regex::new("if|[a-z]+|[0-9]+").unwrap().alternatives_overlap(), vec![(0, 1)]);
+        assert!(Regex::new("ab|ac").unwrap().alternatives_overlap().is_empty());
+        assert!(Regex::from_literals(&["a", "a"]).alternatives_overlap().is_empty());
+    }
+
+    #[test]
+    fn multi_regex() {
+        let mut multi = MultiRegex::new();
+        assert!(!multi.is_match(""));
+        multi.add_pattern("(ab)*c").unwrap();
+        multi.add_pattern("x+").unwrap();
+        assert!(multi.add_pattern("*").is_err());
+        for s in &["abc", "c", "xx"] {
+            assert!(multi.is_match(s), "{:?}", s);
+        }
+        assert!(!multi.is_match("abx"));
+    }
+
+    #[test]
+    fn multi_regex_find_iter() {
+        let mut multi = MultiRegex::new();
+        multi.add_pattern("if").unwrap();
+        multi.add_pattern("[a-z]+").unwrap();
+        assert_eq!(multi.find_iter("if ix").collect::<Vec<_>>(), vec![(0, 0, 2), (1, 3, 5)]);
+        assert_eq!(multi.find_iter("iff").collect::<Vec<_>>(), vec![(1, 0, 3)]);
+
+        let mut anchored = MultiRegex::new();
+        anchored.add_pattern("^a").unwrap();
+        anchored.add_pattern("a").unwrap();
+        assert_eq!(anchored.find_iter("aa").collect::<Vec<_>>(), vec![(0, 0, 1), (1, 1, 2)]);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn large_classes() {
+        let regex = Regex::new(r"\p{L}+\P{L}").unwrap();
+        assert_language(&regex, &["a1", "éΩ ", "ж\u{10ffff}"], &["a", "1", "a1b", "\u{e000}"]);
+        // each class is a few range transitions rather than one per char
+        let metrics = Regex::new(r"\p{C}").unwrap().metrics();
+        assert!(metrics.dfa_transitions < 1000, "{:?}", metrics);
+        assert!(Regex::new(r"\p{C}").unwrap().is_match("\u{0}"));
+        let bytes = Regex::new_bytes(r"\p{Greek}+").unwrap();
+        assert!(bytes.is_match("αβγ".as_bytes()) && !bytes.is_match("αb".as_bytes()));
+        assert!(!Regex::new(r"\p{C}").unwrap().is_match("a"));
+    }
+}
@@ -0,0 +1,26 @@
+// helpers for tests written against Regex
+
+use Regex;
+
+// panics naming the first string in accepts that regex does not match,
+// or the first one in rejects that it does. the NFA simulation behind regex,
+// if any, has to give the same verdicts as its DFA.
+pub fn assert_language(regex: &Regex, accepts: &[&str], rejects: &[&str]) {
+    for s in accepts {
+        if !regex.is_match(s) {
+            panic!("expected {:?} to be accepted, but it was rejected", s);
+        }
+    }
+    for s in rejects {
+        if regex.is_match(s) {
+            panic!("expected {:?} to be rejected, but it was accepted", s);
+        }
+    }
+    if let Some(nfa) = regex.nfa_engine() {
+        for s in accepts.iter().chain(rejects) {
+            if nfa.match_string(s) != regex.is_match(s) {
+                panic!("NfaEngine and the DFA disagree on {:?}", s);
+            }
+        }
+    }
+}
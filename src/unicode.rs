@@ -0,0 +1,97 @@
+// the classes behind `\p{..}`: general categories by their abbreviation,
+// like `Lu`, or by its first letter for the whole group, like `L`, and
+// scripts by their full or short name, like `Greek` or `Grek`
+
+use ranges;
+use std::char;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use unicode_general_category::GeneralCategory;
+use unicode_general_category::get_general_category;
+use unicode_script::Script;
+use unicode_script::UnicodeScript;
+
+const CATEGORIES: &[&str] = &["C", "Cc", "Cf", "Cn", "Co", "Cs", "L", "Ll", "Lm", "Lo", "Lt", "Lu", "M", "Mc", "Me",
+                              "Mn", "N", "Nd", "Nl", "No", "P", "Pc", "Pd", "Pe", "Pf", "Pi", "Po", "Ps", "S", "Sc",
+                              "Sk", "Sm", "So", "Z", "Zl", "Zp", "Zs"];
+
+type RangesByProperty = HashMap<Property, Vec<(char, char)>>;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Property {
+    // an entry of CATEGORIES
+    Category(&'static str),
+    Script(Script),
+}
+
+impl Property {
+    pub fn from_name(name: &str) -> Option<Property> {
+        if let Some(&category) = CATEGORIES.iter().find(|&&category| category == name) {
+            return Some(Property::Category(category));
+        }
+        Script::from_full_name(name).or_else(|| Script::from_short_name(name)).map(Property::Script)
+    }
+
+    pub fn contains(self, c: char) -> bool {
+        match self {
+            Property::Category(category) => abbreviation(get_general_category(c)).starts_with(category),
+            Property::Script(script) => c.script() == script,
+        }
+    }
+
+    // every char with the property, as sorted and disjoint ranges. finding
+    // them takes a look at every char, so each property is only scanned once.
+    pub fn ranges(self) -> Vec<(char, char)> {
+        static RANGES: OnceLock<Mutex<RangesByProperty>> = OnceLock::new();
+        let mut cache = RANGES.get_or_init(Default::default).lock().unwrap();
+        cache.entry(self).or_insert_with(|| self.scan()).clone()
+    }
+
+    fn scan(self) -> Vec<(char, char)> {
+        let mut ranges: Vec<(char, char)> = vec![];
+        for c in (0..0x110000).filter_map(char::from_u32).filter(|&c| self.contains(c)) {
+            match ranges.last_mut() {
+                Some(&mut (_, ref mut last)) if ranges::next(*last) == Some(c) => *last = c,
+                _ => ranges.push((c, c)),
+            }
+        }
+        ranges
+    }
+}
+
+fn abbreviation(category: GeneralCategory) -> &'static str {
+    match category {
+        GeneralCategory::Control => "Cc",
+        GeneralCategory::Format => "Cf",
+        GeneralCategory::PrivateUse => "Co",
+        GeneralCategory::Surrogate => "Cs",
+        GeneralCategory::LowercaseLetter => "Ll",
+        GeneralCategory::ModifierLetter => "Lm",
+        GeneralCategory::OtherLetter => "Lo",
+        GeneralCategory::TitlecaseLetter => "Lt",
+        GeneralCategory::UppercaseLetter => "Lu",
+        GeneralCategory::SpacingMark => "Mc",
+        GeneralCategory::EnclosingMark => "Me",
+        GeneralCategory::NonspacingMark => "Mn",
+        GeneralCategory::DecimalNumber => "Nd",
+        GeneralCategory::LetterNumber => "Nl",
+        GeneralCategory::OtherNumber => "No",
+        GeneralCategory::ConnectorPunctuation => "Pc",
+        GeneralCategory::DashPunctuation => "Pd",
+        GeneralCategory::ClosePunctuation => "Pe",
+        GeneralCategory::FinalPunctuation => "Pf",
+        GeneralCategory::InitialPunctuation => "Pi",
+        GeneralCategory::OtherPunctuation => "Po",
+        GeneralCategory::OpenPunctuation => "Ps",
+        GeneralCategory::CurrencySymbol => "Sc",
+        GeneralCategory::ModifierSymbol => "Sk",
+        GeneralCategory::MathSymbol => "Sm",
+        GeneralCategory::OtherSymbol => "So",
+        GeneralCategory::LineSeparator => "Zl",
+        GeneralCategory::ParagraphSeparator => "Zp",
+        GeneralCategory::SpaceSeparator => "Zs",
+        // Unassigned, and whatever later versions of the tables add
+        _ => "Cn",
+    }
+}